@@ -303,6 +303,7 @@ export class GlobalSettingsModal {
     async loadLibrarySettings() {
         const response = await fetch('/api/config');
         const config = await response.json();
+        this.config = config;
         this.modal.querySelector('#movies_root').value = config.movies_root;
         this.modal.querySelector('#series_root').value = config.series_root;
     }
@@ -316,7 +317,7 @@ export class GlobalSettingsModal {
             headers: {
                 'Content-Type': 'application/json'
             },
-            body: JSON.stringify({ movies_root, series_root })
+            body: JSON.stringify({ ...this.config, movies_root, series_root })
         });
 
         this.showFeedback('Library settings saved!');
@@ -19,16 +19,29 @@ export class Track {
 }
 
 export class VideoMetadata {
-  constructor(duration, tracks, unavailableSubs) {
+  constructor(duration, tracks, unavailableSubs, videoCodec, audioCodec, playback, qualities) {
     this.duration = duration;
     this.tracks = tracks;
     this.unavailableSubs = unavailableSubs;
+    this.videoCodec = videoCodec;
+    this.audioCodec = audioCodec;
+    this.playback = playback;
+    // Quality profiles offered for this video, smallest first
+    this.qualities = qualities;
   }
 
   static fromJson(json) {
     const tracks = Track.fromJsonArray(json.tracks);
     const unavailableSubs = json.unavailable_subs;
-    return new VideoMetadata(json.duration, tracks, unavailableSubs);
+    return new VideoMetadata(
+      json.duration,
+      tracks,
+      unavailableSubs,
+      json.video_codec,
+      json.audio_codec,
+      json.playback,
+      json.qualities || [],
+    );
   }
 
   getAudioTracks() {
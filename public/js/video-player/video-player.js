@@ -13,6 +13,8 @@ export class VideoPlayer {
 		this.videoElement = document.getElementById(videoElementId);
 		this.videoPath = encodeURIComponent(videoPath);
 		this.watchHistory = watchHistory;
+		// Lets the server cancel our previous chunk when we seek.
+		this.sessionId = Math.random().toString(36).slice(2);
 		this.videoMimeType = 'video/mp4 ; codecs="hvc1.1.6.L93.B0"';
 		this.audioMimeType = 'audio/mp4 ; codecs="opus"';
 		this.mediaSource = null;
@@ -24,9 +26,19 @@ export class VideoPlayer {
 		this.player = null;
 		this.audioIdx = 0;
 		this.subtitleTrackElements = [];
+		// Seek bar previews, each a tile of a sprite sheet
+		this.thumbnails = [];
 		this.seekDuration = 0;
 		this.seekDelay = 500; // in milliseconds
 		this.seekTimer = null;
+		// Chosen from the measured throughput when the server transcodes
+		this.quality = null;
+		this.throughput = null; // in bits per second
+		// HDR sources are tone mapped unless the display and MSE can show HDR
+		this.hdr =
+			window.matchMedia("(dynamic-range: high)").matches &&
+			"MediaSource" in window &&
+			MediaSource.isTypeSupported('video/mp4; codecs="hvc1.2.4.L153.B0"');
 
 		if ("MediaSource" in window) {
 			this.initializeMediaSource();
@@ -367,6 +379,7 @@ export class VideoPlayer {
 				this.player.currentTime(this.watchHistory.watched_duration);
 			}
 			await this.fetchSubtitles();
+			this.loadThumbnails();
 			await this.initializeSourceBuffer();
 			await this.fetchVideoChunk(this.watchHistory ? this.watchHistory.watched_duration : 0.0);
 		});
@@ -444,8 +457,68 @@ export class VideoPlayer {
 		this.audioSourceBuffer = audioSourceBuffer;
 	}
 
+	// What this browser can decode, so the server only transcodes what it must
+	deviceProfile() {
+		const supported = (type, codecs) =>
+			MediaSource.isTypeSupported(`${type}; codecs="${codecs}"`);
+		const videoCodecs = [
+			["h264", "avc1.640028"],
+			["hevc", "hvc1.1.6.L93.B0"],
+		];
+		const audioCodecs = [
+			["aac", "mp4a.40.2"],
+			["opus", "opus"],
+			["ac3", "ac-3"],
+			["eac3", "ec-3"],
+			["flac", "flac"],
+		];
+		const containers = [
+			["mp4", 'video/mp4; codecs="avc1.640028"'],
+			["webm", 'video/webm; codecs="vp9"'],
+			["mkv", 'video/x-matroska; codecs="avc1.640028"'],
+		];
+		const { width, height } = window.screen;
+		// Surround is only kept for outputs with more than two channels; stereo
+		// outputs leave it unset so surround sources can still be copied
+		let audioChannels = 2;
+		if (window.AudioContext) {
+			const context = new AudioContext();
+			audioChannels = context.destination.maxChannelCount || 2;
+			context.close();
+		}
+		return {
+			video_codecs: videoCodecs
+				.filter(([, codecs]) => supported("video/mp4", codecs))
+				.map(([name]) => name),
+			audio_codecs: audioCodecs
+				.filter(([, codecs]) => supported("audio/mp4", codecs))
+				.map(([name]) => name),
+			containers: containers
+				.filter(([, type]) => this.videoElement.canPlayType(type) === "probably")
+				.map(([name]) => name),
+			// Screen size in device pixels, in landscape as videos usually are
+			max_width: Math.round(Math.max(width, height) * window.devicePixelRatio),
+			max_height: Math.round(Math.min(width, height) * window.devicePixelRatio),
+			hdr: this.hdr,
+			audio_channels: audioChannels > 2 ? audioChannels : undefined,
+		};
+	}
+
+	// Registers the device profile; the session id then selects it on the server
+	async registerSession() {
+		const response = await fetch("/video-session", {
+			method: "POST",
+			headers: { "Content-Type": "application/json" },
+			body: JSON.stringify(this.deviceProfile()),
+		});
+		if (!response.ok) return;
+		const data = await response.json();
+		this.sessionId = data.session;
+	}
+
 	async loadInitialMetadata() {
-		const response = await fetch(`/video-data?path=${this.videoPath}`);
+		await this.registerSession();
+		const response = await fetch(`/video-data?path=${this.videoPath}&session=${this.sessionId}`);
 		if (!response.ok) throw new Error("Failed to fetch video duration");
 
 		const data = await response.json();
@@ -453,10 +526,16 @@ export class VideoPlayer {
 
 		this.videoMetadata = videoMetadata;
 		this.mediaSource.duration = this.videoMetadata.duration;
+		if (this.videoMetadata.videoCodec) {
+			this.videoMimeType = `video/mp4 ; codecs="${this.videoMetadata.videoCodec}"`;
+		}
+		if (this.videoMetadata.audioCodec) {
+			this.audioMimeType = `audio/mp4 ; codecs="${this.videoMetadata.audioCodec}"`;
+		}
 	}
 
 	async fetchSubtitles() {
-		// Add track fields and subtitle data
+		// Add track fields; their cues are loaded when they are first shown
 		const subtitleTracks = this.videoMetadata.getSubtitleTracks();
 		for (let i = 0; i < subtitleTracks.length; i++) {
 			if (this.videoMetadata.unavailableSubs.includes(i)) continue;
@@ -466,14 +545,116 @@ export class VideoPlayer {
 				kind: "subtitles",
 				label: subtitleTrack.label,
 				srclang: "en",
-				//src: url,
 			});
 
 			// Store track reference for later updates
-			this.subtitleTrackElements.push({ idx: i, element: track });
+			this.subtitleTrackElements.push({ idx: i, element: track, loaded: false });
+		}
+
+		this.player.textTracks().addEventListener("change", () => {
+			for (const trackElement of this.subtitleTrackElements) {
+				if (trackElement.element.track.mode === "showing" && !trackElement.loaded) {
+					trackElement.loaded = true;
+					this.loadSubtitleTrack(trackElement);
+				}
+			}
+		});
+	}
+
+	/**
+	 * Fetches a whole subtitle track at once, so cues are never cut at chunk
+	 * boundaries.
+	 */
+	async loadSubtitleTrack(trackElement) {
+		try {
+			const response = await fetch(
+				`/subtitles/${trackElement.idx}?path=${this.videoPath}&format=vtt`,
+			);
+			if (!response.ok) {
+				throw new Error(`HTTP error! status: ${response.status}`);
+			}
+			const subtitleText = await response.text();
+			const parser = new WebVTTParser();
+			const subtitleCues = parser.parse(subtitleText, "subtitles");
+			const vjsTexttrack = trackElement.element.track;
+			for (let k = 0; k < subtitleCues.cues.length; k++) {
+				vjsTexttrack.addCue(subtitleCues.cues[k]);
+			}
+		} catch (error) {
+			trackElement.loaded = false;
+			console.error("Error fetching subtitles:", error.message);
 		}
 	}
 
+	/**
+	 * Fetches the seek bar thumbnails. The server may still be generating
+	 * them, in which case it says when to ask again.
+	 */
+	async loadThumbnails() {
+		try {
+			const url = `/trickplay/thumbnails.vtt?path=${this.videoPath}`;
+			const response = await fetch(url);
+			if (response.status === 202) {
+				const retryAfter = parseInt(response.headers.get("Retry-After"), 10) || 30;
+				setTimeout(() => this.loadThumbnails(), retryAfter * 1000);
+				return;
+			}
+			if (!response.ok) return;
+			const base = new URL(url, window.location.href);
+			const parser = new WebVTTParser();
+			this.thumbnails = parser.parse(await response.text()).cues.map((cue) => {
+				const [image, fragment] = cue.text.split("#xywh=");
+				const [x, y, width, height] = fragment.split(",").map(Number);
+				return {
+					start: cue.startTime,
+					end: cue.endTime,
+					url: new URL(image, base).href,
+					x,
+					y,
+					width,
+					height,
+				};
+			});
+			this.setupThumbnailPreview();
+		} catch (error) {
+			console.error("Error fetching thumbnails:", error.message);
+		}
+	}
+
+	// Shows the thumbnail of the time under the mouse above the seek bar
+	setupThumbnailPreview() {
+		const seekBar = this.player.controlBar.progressControl.seekBar;
+		const preview = document.createElement("div");
+		preview.className = "vjs-thumbnail-preview";
+		seekBar.el().appendChild(preview);
+
+		seekBar.on("mousemove", (event) => {
+			const rect = seekBar.el().getBoundingClientRect();
+			const offset = Math.min(Math.max(event.clientX - rect.left, 0), rect.width);
+			const time = (offset / rect.width) * this.videoMetadata.duration;
+			const thumbnail = this.thumbnails.find(
+				(thumbnail) => time >= thumbnail.start && time < thumbnail.end,
+			);
+			if (!thumbnail) {
+				preview.style.display = "none";
+				return;
+			}
+			// Kept within the seek bar at either end
+			const half = thumbnail.width / 2;
+			Object.assign(preview.style, {
+				display: "block",
+				left: `${Math.min(Math.max(offset, half), rect.width - half)}px`,
+				width: `${thumbnail.width}px`,
+				height: `${thumbnail.height}px`,
+				backgroundImage: `url("${thumbnail.url}")`,
+				backgroundPosition: `-${thumbnail.x}px -${thumbnail.y}px`,
+			});
+		});
+		seekBar.on("mouseleave", () => {
+			preview.style.display = "none";
+		});
+	}
+
 	async fetchVideoChunk(startTime) {
 		if (
 			this.isFetching ||
@@ -496,19 +677,34 @@ export class VideoPlayer {
 
 			this.videoSourceBuffer.timestampOffset = startTime;
 			this.audioSourceBuffer.timestampOffset = startTime;
+			const quality = this.quality ? `&quality=${this.quality}` : "";
+			const fetchStarted = performance.now();
 			const response = await fetch(
-				`/video?path=${this.videoPath}&timestamp=${startTime}&duration=20`,
+				`/video?path=${this.videoPath}&timestamp=${startTime}&duration=20&session=${this.sessionId}${quality}`,
 			);
 			if (!response.ok) {
 				throw new Error("Failed to fetch video chunk");
 			}
 
 			const arrayBuffer = await response.arrayBuffer();
+			this.updateQuality(
+				arrayBuffer.byteLength,
+				(performance.now() - fetchStarted) / 1000,
+			);
 
 			// Parse the binary data using the VideoResponseParser class
 			const parser = new VideoResponseParser(arrayBuffer);
 			const parsedData = parser.parse();
 
+			// The codec changes when a quality switch turns a copy into a transcode
+			const videoMimeType = parsedData.videoCodec
+				? `video/mp4 ; codecs="${parsedData.videoCodec}"`
+				: this.videoMimeType;
+			if (videoMimeType !== this.videoMimeType && this.videoSourceBuffer.changeType) {
+				this.videoSourceBuffer.changeType(videoMimeType);
+				this.videoMimeType = videoMimeType;
+			}
+
 			// Append the video data to the video source buffer
 			if (this.videoSourceBuffer && !this.videoSourceBuffer.updating) {
 				this.videoSourceBuffer.appendBuffer(parsedData.videoData);
@@ -530,32 +726,6 @@ export class VideoPlayer {
 					}),
 				);
 			}
-
-			// Append subtitle data to track elements
-			for (let i = 0; i < parsedData.numSubTracks; i++) {
-				const subtitleTrackData = parsedData.subtitleTracks[i];
-				const trackElement = this.subtitleTrackElements.find(
-					(track) => track.idx === Number(subtitleTrackData.id),
-				);
-				let subtitleText = new TextDecoder("utf-8").decode(
-					subtitleTrackData.data,
-				);
-				let vjsTexttracks = this.player.textTracks();
-				for (let j = 0; j < vjsTexttracks.length; j++) {
-					if (vjsTexttracks[j].label === trackElement.element.label) {
-						let vjsTexttrack = vjsTexttracks[j];
-						// Remove all existing cues
-						while (vjsTexttrack.cues.length > 0) {
-							vjsTexttrack.removeCue(vjsTexttrack.cues[0]);
-						}
-						const parser = new WebVTTParser();
-						const subtitleCues = parser.parse(subtitleText, "subtitles");
-						for (let k = 0; k < subtitleCues.cues.length; k++) {
-							vjsTexttrack.addCue(subtitleCues.cues[k]);
-						}
-					}
-				}
-			}
 		} catch (error) {
 			console.error("Error fetching video chunk:", error.message);
 		} finally {
@@ -563,6 +733,32 @@ export class VideoPlayer {
 		}
 	}
 
+	/**
+	 * Picks the largest quality profile the connection keeps up with, from a
+	 * moving average of the chunk download rate. Only transcoded streams can
+	 * switch quality; copied streams keep the source's codec.
+	 */
+	updateQuality(bytes, seconds) {
+		const qualities = this.videoMetadata?.qualities || [];
+		if (this.videoMetadata?.playback?.mode !== "transcode" || qualities.length === 0 || seconds <= 0) {
+			return;
+		}
+		const sample = (bytes * 8) / seconds;
+		this.throughput = this.throughput === null ? sample : 0.7 * this.throughput + 0.3 * sample;
+
+		const audioBitrate = 128000;
+		let chosen = qualities[0];
+		for (const quality of qualities) {
+			if (quality.max_bitrate * 1000 + audioBitrate <= 0.8 * this.throughput) {
+				chosen = quality;
+			}
+		}
+		if (chosen.name !== this.quality) {
+			console.log(`Switching quality to ${chosen.name}`);
+			this.quality = chosen.name;
+		}
+	}
+
 	async bufferNextVideoChunk(currentTime) {
 		try {
 			if (!this.videoSourceBuffer || !this.audioSourceBuffer) {
@@ -1,6 +1,32 @@
 /**
  * Binary video response parser for handling video stream data
  */
+
+// Version 2 responses start with these bytes ("NXFV")
+const MAGIC = [0x4e, 0x58, 0x46, 0x56];
+const TRACK_KINDS = ["video", "audio", "subtitle"];
+
+// CRC-32 as used by zlib and PNG, to check version 2 track checksums
+const CRC32_TABLE = (() => {
+  const table = new Uint32Array(256);
+  for (let i = 0; i < 256; i++) {
+    let value = i;
+    for (let bit = 0; bit < 8; bit++) {
+      value = value & 1 ? (value >>> 1) ^ 0xedb88320 : value >>> 1;
+    }
+    table[i] = value >>> 0;
+  }
+  return table;
+})();
+
+function crc32(data) {
+  let crc = 0xffffffff;
+  for (let i = 0; i < data.length; i++) {
+    crc = CRC32_TABLE[(crc ^ data[i]) & 0xff] ^ (crc >>> 8);
+  }
+  return (crc ^ 0xffffffff) >>> 0;
+}
+
 export class VideoResponseParser {
   constructor(arrayBuffer) {
     this.arrayBuffer = arrayBuffer;
@@ -13,6 +39,21 @@ export class VideoResponseParser {
     this.videoData = null;
     this.audioTracks = [];
     this.subtitleTracks = [];
+    // Only set by version 2 responses
+    this.version = 1;
+    this.startPts = null;
+    this.videoCodec = null;
+  }
+
+  readUint8() {
+    const value = this.dataView.getUint8(this.offset);
+    this.offset += 1;
+    return value;
+  }
+
+  readString() {
+    const length = this.readUint8();
+    return new TextDecoder("utf-8").decode(this.readBytes(length));
   }
 
   // Helper method to read a Uint32
@@ -46,9 +87,100 @@ export class VideoResponseParser {
     return value;
   }
 
+  // Read one track: a u64 id followed by length-prefixed chunks, terminated
+  // by a zero length chunk. Version 2 has the id in the header instead.
+  readTrack() {
+    const id = this.readBigUint64();
+    return { id, data: this.readChunks() };
+  }
+
+  readChunks() {
+    const chunks = [];
+    let length = 0;
+    for (;;) {
+      const chunkLength = this.readUint32();
+      if (chunkLength === 0) break;
+      const chunk = this.readBytes(chunkLength);
+      chunks.push(chunk);
+      length += chunkLength;
+    }
+    const data = new Uint8Array(length);
+    let offset = 0;
+    for (const chunk of chunks) {
+      data.set(chunk, offset);
+      offset += chunk.length;
+    }
+    return data;
+  }
+
+  isVersion2() {
+    if (this.dataView.byteLength < MAGIC.length) return false;
+    return MAGIC.every((byte, i) => this.dataView.getUint8(i) === byte);
+  }
+
+  // Version 2: a header describing every track, then their data in order
+  parseVersion2() {
+    this.offset = MAGIC.length;
+    this.version = this.dataView.getUint16(this.offset, true);
+    this.offset += 2;
+    const checksums = (this.readUint8() & 1) === 1;
+    this.readUint8(); // reserved
+    this.startPts = this.dataView.getFloat64(this.offset, true);
+    this.offset += 8;
+    const trackCount = this.readUint32();
+    const headers = [];
+    for (let i = 0; i < trackCount; i++) {
+      const id = this.readBigUint64();
+      const kind = TRACK_KINDS[this.readUint8()];
+      const codec = this.readString();
+      const language = this.readString() || null;
+      headers.push({ id, kind, codec, language });
+    }
+    for (const header of headers) {
+      const track = { ...header, data: this.readChunks() };
+      if (checksums) {
+        const expected = this.readUint32();
+        if (crc32(track.data) !== expected) {
+          throw new Error(`Checksum mismatch in ${track.kind} track ${track.id}`);
+        }
+      }
+      if (track.kind === "video") {
+        this.videoData = track.data;
+        this.videoCodec = track.codec;
+      } else if (track.kind === "audio") {
+        this.audioTracks.push(track);
+      } else {
+        this.subtitleTracks.push(track);
+      }
+    }
+    this.numAudioTracks = this.audioTracks.length;
+    this.numSubTracks = this.subtitleTracks.length;
+    if (!this.videoData || this.videoData.length === 0) {
+      throw new Error("Empty video track");
+    }
+  }
+
+  result() {
+    return {
+      version: this.version,
+      startPts: this.startPts,
+      videoCodec: this.videoCodec,
+      numAudioTracks: this.numAudioTracks,
+      numSubTracks: this.numSubTracks,
+      videoData: this.videoData,
+      audioTracks: this.audioTracks,
+      subtitleTracks: this.subtitleTracks,
+    };
+  }
+
   // Main method to parse the binary data
   parse() {
     try {
+      if (this.isVersion2()) {
+        this.parseVersion2();
+        return this.result();
+      }
+
       // Read and validate the number of audio tracks
       this.numAudioTracks = this.readUint32();
       if (this.numAudioTracks < 0 || this.numAudioTracks > 100) {
@@ -57,47 +189,24 @@ export class VideoResponseParser {
         );
       }
       this.numSubTracks = this.readUint32();
-      // Read and validate the video track length
-      const videoTrackLength = Number(this.readBigUint64());
-      if (
-        videoTrackLength <= 0 ||
-        videoTrackLength > this.dataView.byteLength
-      ) {
-        throw new Error(`Invalid video track length: ${videoTrackLength}`);
+
+      const videoTrack = this.readTrack();
+      if (videoTrack.data.length === 0) {
+        throw new Error("Empty video track");
       }
-      this.videoData = this.readBytes(videoTrackLength);
+      this.videoData = videoTrack.data;
 
       // Read and store audio tracks
       for (let i = 0; i < this.numAudioTracks; i++) {
-        const trackId = this.readBigUint64();
-        const trackLength = Number(this.readBigUint64());
-
-        if (trackLength <= 0 || trackLength > this.dataView.byteLength) {
-          throw new Error(`Invalid audio track length: ${trackLength}`);
-        }
-        const trackData = this.readBytes(trackLength);
-        this.audioTracks.push({ id: trackId, data: trackData });
+        this.audioTracks.push(this.readTrack());
       }
 
       // Read and store subtitle tracks
       for (let i = 0; i < this.numSubTracks; i++) {
-        const trackId = this.readBigUint64();
-        const trackLength = Number(this.readBigUint64());
-        if (trackLength <= 0 || trackLength > this.dataView.byteLength) {
-          throw new Error(`Invalid subtitle track length: ${trackLength}`);
-        }
-        const trackData = this.readBytes(trackLength);
-        this.subtitleTracks.push({ id: trackId, data: trackData });
+        this.subtitleTracks.push(this.readTrack());
       }
 
-      // Return parsed data
-      return {
-        numAudioTracks: this.numAudioTracks,
-        numSubTracks: this.numSubTracks,
-        videoData: this.videoData,
-        audioTracks: this.audioTracks,
-        subtitleTracks: this.subtitleTracks,
-      };
+      return this.result();
     } catch (error) {
       console.error("Error parsing video data:", error.message);
       throw error;
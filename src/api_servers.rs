@@ -7,6 +7,8 @@ use hyper::{header, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Meta {
@@ -57,6 +59,85 @@ pub struct MetaData {
     file_database: HashMap<String, Value>,
     #[serde(default)]
     watch_history: HashMap<String, WatchHistory>,
+    /// Encodings to read subtitle files in instead of the detected ones, by
+    /// subtitle file path.
+    #[serde(default)]
+    subtitle_encodings: HashMap<String, String>,
+}
+
+fn metadata_file() -> PathBuf {
+    let data_path = directories::ProjectDirs::from("com", "dr42", "nexus").unwrap();
+    data_path.data_dir().join("meta.json")
+}
+
+/// The library database, or `None` if it has not been saved yet.
+fn read_metadata() -> Result<Option<MetaData>, String> {
+    let json_data = match std::fs::read_to_string(metadata_file()) {
+        Ok(json_data) => json_data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read library: {e}")),
+    };
+    serde_json::from_str(&json_data)
+        .map(Some)
+        .map_err(|e| format!("Failed to parse library: {e}"))
+}
+
+/// Held across every read-modify-write of `meta.json`, so concurrent updates
+/// do not drop each other's changes. Taken before `SUBTITLE_ENCODINGS`.
+static METADATA_LOCK: Mutex<()> = Mutex::new(());
+
+fn empty_metadata() -> MetaData {
+    MetaData {
+        series: vec![],
+        movies: vec![],
+        file_database: HashMap::new(),
+        watch_history: HashMap::new(),
+        subtitle_encodings: HashMap::new(),
+    }
+}
+
+fn write_metadata(media_data: &MetaData) -> Result<(), String> {
+    let metadata_file = metadata_file();
+    let json_data = serde_json::to_string_pretty(media_data).unwrap();
+    metadata_file
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(metadata_file, json_data))
+        .map_err(|e| format!("Failed to save library: {e}"))
+}
+
+/// `subtitle_encodings` of the library, read once and kept up to date by
+/// `set_subtitle_encoding`.
+static SUBTITLE_ENCODINGS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(|| {
+    Mutex::new(
+        read_metadata()
+            .ok()
+            .flatten()
+            .map(|media_data| media_data.subtitle_encodings)
+            .unwrap_or_default(),
+    )
+});
+
+/// The encoding set for a subtitle file, overriding detection.
+pub fn subtitle_encoding(path: &Path) -> Option<String> {
+    let encodings = SUBTITLE_ENCODINGS.lock().unwrap();
+    encodings.get(path.to_string_lossy().as_ref()).cloned()
+}
+
+/// Sets or, with `None`, removes the encoding of a subtitle file. A library
+/// database that cannot be read is left alone rather than overwritten.
+pub fn set_subtitle_encoding(path: &Path, encoding: Option<String>) -> Result<(), String> {
+    let _library = METADATA_LOCK.lock().unwrap();
+    let mut encodings = SUBTITLE_ENCODINGS.lock().unwrap();
+    let mut media_data = read_metadata()?.unwrap_or_else(empty_metadata);
+    let path = path.to_string_lossy().to_string();
+    match encoding {
+        Some(encoding) => media_data.subtitle_encodings.insert(path, encoding),
+        None => media_data.subtitle_encodings.remove(&path),
+    };
+    write_metadata(&media_data)?;
+    *encodings = media_data.subtitle_encodings;
+    Ok(())
 }
 
 pub async fn add_media(media: Json<MetaData>) -> impl IntoResponse {
@@ -67,7 +148,15 @@ pub async fn add_media(media: Json<MetaData>) -> impl IntoResponse {
         std::fs::create_dir_all(data_dir).unwrap();
     }
     let metadata_file = data_dir.join("meta.json");
-    let media_data = media.0;
+    let mut media_data = media.0;
+    let _library = METADATA_LOCK.lock().unwrap();
+    // The client does not know about the encodings, so keep them.
+    let mut encodings = SUBTITLE_ENCODINGS.lock().unwrap();
+    if media_data.subtitle_encodings.is_empty() {
+        media_data.subtitle_encodings = encodings.clone();
+    } else {
+        *encodings = media_data.subtitle_encodings.clone();
+    }
     let json_data = serde_json::to_string_pretty(&media_data).unwrap();
     std::fs::write(metadata_file, json_data).unwrap();
     Response::builder()
@@ -97,28 +186,20 @@ pub async fn get_media() -> impl IntoResponse {
 }
 
 pub async fn update_watch_history(Json(watch_history): Json<WatchHistory>) -> impl IntoResponse {
-    let data_path = directories::ProjectDirs::from("com", "dr42", "nexus").unwrap();
-    let data_dir = data_path.data_dir();
-    let metadata_file = data_dir.join("meta.json");
-
-    let mut media_data: MetaData = if metadata_file.exists() {
-        let json_data = std::fs::read_to_string(&metadata_file).unwrap();
-        serde_json::from_str(&json_data).unwrap()
-    } else {
-        MetaData {
-            series: vec![],
-            movies: vec![],
-            file_database: HashMap::new(),
-            watch_history: HashMap::new(),
-        }
-    };
-
-    media_data
-        .watch_history
-        .insert(watch_history.media_id.clone(), watch_history);
-
-    let json_data = serde_json::to_string_pretty(&media_data).unwrap();
-    std::fs::write(metadata_file, json_data).unwrap();
+    let _library = METADATA_LOCK.lock().unwrap();
+    let updated = read_metadata().and_then(|media_data| {
+        let mut media_data = media_data.unwrap_or_else(empty_metadata);
+        media_data
+            .watch_history
+            .insert(watch_history.media_id.clone(), watch_history);
+        write_metadata(&media_data)
+    });
+    if let Err(e) = updated {
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e))
+            .unwrap();
+    }
 
     Response::builder()
         .status(StatusCode::OK)
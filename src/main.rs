@@ -1,10 +1,11 @@
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router, Extension,
 };
 use serde::Serialize;
 use std::env;
 use std::env::args;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 mod api_servers;
@@ -42,13 +43,47 @@ async fn main() {
     
     // Initialize TMDB API
     let tmdb_api = Arc::new(tmdb_api::TmdbApi::new().expect("Failed to initialize TMDB API"));
+    let config = video_servers::load_config();
+    let ffmpeg = match video_servers::check_ffmpeg(&config).await {
+        Ok(ffmpeg) => ffmpeg,
+        Err(e) => {
+            eprintln!("FFmpeg check failed: {e}");
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Using ffmpeg {} with the {:?} encoder backend",
+        ffmpeg.ffmpeg_version, ffmpeg.encoder
+    );
+    let ffmpeg = Arc::new(ffmpeg);
+    let segment_cache = Arc::new(video_servers::SegmentCache::new(&config, video_servers::cache_dir().join("segments")));
+    let transcode_manager = Arc::new(video_servers::TranscodeManager::new(&config, segment_cache.clone()));
     
     let app = Router::new();
     let app = add_route!(app, get, "/", web_servers::serve_index);
     let app = add_route!(app, get, "/favicon.png", web_servers::serve_favicon);
     let app = add_route!(app, get, "/video", video_servers::serve_video);
     let app = add_route!(app, get, "/video-data", video_servers::serve_video_metadata);
+    let app = add_route!(app, post, "/video-session", video_servers::create_session);
+    let app = add_route!(app, get, "/subtitles/{track}", video_servers::serve_subtitle_track);
+    let app = add_route!(app, post, "/subtitles/{track}/encoding", video_servers::set_subtitle_encoding);
+    let app = add_route!(app, get, "/fonts/{id}", video_servers::serve_font);
+    let app = add_route!(app, get, "/trickplay/thumbnails.vtt", video_servers::serve_thumbnails);
+    let app = add_route!(app, get, "/trickplay/sheets/{sheet}", video_servers::serve_sheet);
+    let app = add_route!(app, get, "/direct", video_servers::serve_raw_file);
     let app = add_route!(app, get, "/file_list", video_servers::serve_file_list);
+    // HLS streaming
+    let app = add_route!(app, get, "/hls/master.m3u8", video_servers::hls::serve_master_playlist);
+    let app = add_route!(app, get, "/hls/media.m3u8", video_servers::hls::serve_media_playlist);
+    let app = add_route!(app, get, "/hls/init.mp4", video_servers::hls::serve_init_segment);
+    let app = add_route!(app, get, "/hls/segment.ts", video_servers::hls::serve_segment);
+    let app = add_route!(app, get, "/hls/segment.m4s", video_servers::hls::serve_segment);
+    let app = add_route!(app, get, "/hls/segment.vtt", video_servers::hls::serve_segment);
+    // DASH streaming
+    let app = add_route!(app, get, "/dash/manifest.mpd", video_servers::dash::serve_manifest);
+    let app = add_route!(app, get, "/dash/init.mp4", video_servers::dash::serve_init_segment);
+    let app = add_route!(app, get, "/dash/segment.m4s", video_servers::dash::serve_media_segment);
+    let app = add_route!(app, get, "/dash/subtitle.vtt", video_servers::dash::serve_subtitle);
     let app = add_route!(app, post, "/api/add-media", api_servers::add_media);
     let app = add_route!(app, get, "/api/get-media", api_servers::get_media);
     let app = add_route!(app, post, "/api/update-watch-history", api_servers::update_watch_history);
@@ -58,6 +93,12 @@ async fn main() {
     let app = add_route!(app, get, "/api/config", video_servers::get_config);
     let app = add_route!(app, post, "/api/config", video_servers::update_config);
     let app = add_route!(app, get, "/api/browse", video_servers::browse);
+    let app = add_route!(app, get, "/api/system/ffmpeg", video_servers::get_ffmpeg_info);
+    let app = add_route!(app, get, "/api/system/processes", video_servers::get_process_counts);
+    let app = add_route!(app, get, "/api/admin/sessions", video_servers::get_sessions);
+    let app = add_route!(app, get, "/api/system/read-ahead", video_servers::get_read_ahead_stats);
+    let app = add_route!(app, get, "/api/admin/segment-cache", video_servers::get_segment_cache);
+    let app = add_route!(app, delete, "/api/admin/segment-cache", video_servers::purge_segment_cache);
     // Placeholder image
     let app = add_route!(app, get, "/api/placeholder", tmdb_api::serve_placeholder_image);
     // TMDB API routes
@@ -96,9 +137,12 @@ async fn main() {
     // CSS
     let app = add_route!(app, get, "/public/css/style.css", web_servers::serve_style);
     let app = app.layer(Extension(tmdb_api));
+    let app = app.layer(Extension(ffmpeg));
+    let app = app.layer(Extension(transcode_manager));
+    let app = app.layer(Extension(segment_cache));
 
     let addr = format!("0.0.0.0:{port}");
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
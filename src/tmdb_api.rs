@@ -17,6 +17,7 @@ pub struct TmdbApi {
     image_base_url: String,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 pub struct TmdbResponse {
     pub results: Option<Vec<Value>>,
@@ -1,10 +1,12 @@
 use std::fs::{self, File};
 use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use axum::{
     body::Body,
-    extract::Query,
+    extract::{ConnectInfo, Extension, Query},
     http::status::StatusCode,
     response::{IntoResponse, Response},
     Json,
@@ -14,24 +16,194 @@ use hyper::header::{self};
 use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
+mod attachments;
+mod charset;
+pub mod dash;
+mod device_profile;
+mod encoders;
+mod ffmpeg;
+mod framing;
+pub mod hls;
+mod languages;
+mod ocr;
+mod playback;
+mod probe;
+mod probe_cache;
+mod processes;
+mod quality;
+mod raw_file;
+mod read_ahead;
+mod segment_cache;
+mod sidecar;
+mod subtitles;
+mod transcode_manager;
+mod trickplay;
 mod video_helpers;
 
+pub use attachments::serve_font;
+pub use device_profile::create_session;
+pub use encoders::EncoderBackend;
+use encoders::{AudioCodec, Normalization};
+pub use ffmpeg::{check_ffmpeg, FfmpegInfo};
+pub use processes::get_process_counts;
+pub use raw_file::serve_raw_file;
+pub use read_ahead::get_read_ahead_stats;
+pub use segment_cache::{get_segment_cache, purge_segment_cache, SegmentCache};
+pub use subtitles::{serve_subtitle_track, set_subtitle_encoding};
+pub use transcode_manager::{get_sessions, TranscodeManager};
+pub use trickplay::{serve_sheet, serve_thumbnails};
+
 #[derive(Deserialize)]
 pub struct VideoRequest {
     pub path: String,
     pub timestamp: Option<f64>,
     pub duration: Option<f64>,
+    /// Identifies one player instance; a new request supersedes the
+    /// previous one of the same session. A session from `/video-session`
+    /// also selects the device profile.
+    pub session: Option<String>,
+    /// Quality profile, e.g. `720p`; see `/video-data` for those offered.
+    pub quality: Option<String>,
+    /// Whether the client displays HDR, overriding the device profile.
+    pub hdr: Option<bool>,
+    /// Audio settings, overriding the device profile.
+    pub audio_codec: Option<AudioCodec>,
+    pub channels: Option<u32>,
+    pub normalization: Option<Normalization>,
+    /// Response framing version, 1 or 2 (the default).
+    pub protocol: Option<u16>,
+    /// Version 2 only: end every track with a CRC-32 of its data.
+    pub checksums: Option<bool>,
+    /// Bitmap subtitle track to burn into the video, which is then
+    /// re-encoded.
+    pub burn_subtitle: Option<u64>,
 }
 
 #[derive(Deserialize)]
 pub struct VideoMetadataRequest {
     pub path: String,
+    /// Session from `/video-session` whose device profile to decide for.
+    pub session: Option<String>,
+    pub hdr: Option<bool>,
+    pub audio_codec: Option<AudioCodec>,
+    pub channels: Option<u32>,
+    pub normalization: Option<Normalization>,
+}
+
+/// Request parameters that override parts of the device profile.
+struct ProfileOverrides {
+    hdr: Option<bool>,
+    audio_codec: Option<AudioCodec>,
+    channels: Option<u32>,
+    normalization: Option<Normalization>,
+}
+
+/// The device profile of a session, with the request's overrides applied.
+fn client_profile(
+    session: Option<&str>,
+    overrides: ProfileOverrides,
+) -> device_profile::DeviceProfile {
+    let mut profile = device_profile::lookup(session);
+    if let Some(hdr) = overrides.hdr {
+        profile.hdr = hdr;
+    }
+    if let Some(codec) = overrides.audio_codec {
+        profile.audio_codec = Some(codec);
+    }
+    if let Some(channels) = overrides.channels {
+        profile.audio_channels = Some(channels);
+    }
+    if let Some(normalization) = overrides.normalization {
+        profile.normalization = normalization;
+    }
+    profile
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub series_root: String,
     pub movies_root: String,
+    /// Video encoder backend; auto-detected at startup when unset.
+    #[serde(default)]
+    pub encoder: Option<EncoderBackend>,
+    #[serde(default = "default_ffmpeg_path")]
+    pub ffmpeg_path: String,
+    #[serde(default = "default_ffprobe_path")]
+    pub ffprobe_path: String,
+    /// Transcodes allowed to run at once across all users.
+    #[serde(default = "default_max_transcodes")]
+    pub max_transcodes: usize,
+    #[serde(default = "default_max_transcodes_per_user")]
+    pub max_transcodes_per_user: usize,
+    /// How long a request waits for a free transcode slot before it is
+    /// rejected.
+    #[serde(default = "default_transcode_queue_timeout_secs")]
+    pub transcode_queue_timeout_secs: u64,
+    /// Chunks transcoded ahead of each `/video` request; 0 disables it.
+    #[serde(default = "default_read_ahead_chunks")]
+    pub read_ahead_chunks: usize,
+    /// Size cap of the on-disk segment cache; 0 disables it.
+    #[serde(default = "default_segment_cache_max_mb")]
+    pub segment_cache_max_mb: u64,
+    /// tesseract binary used to read bitmap subtitles; OCR is off when unset.
+    #[serde(default)]
+    pub tesseract_path: Option<String>,
+    /// Seconds between seek bar thumbnails; 0 turns them off.
+    #[serde(default = "default_trickplay_interval_secs")]
+    pub trickplay_interval_secs: u64,
+}
+
+fn default_ffmpeg_path() -> String {
+    "ffmpeg".to_string()
+}
+
+fn default_ffprobe_path() -> String {
+    "ffprobe".to_string()
+}
+
+fn default_max_transcodes() -> usize {
+    4
+}
+
+fn default_max_transcodes_per_user() -> usize {
+    2
+}
+
+fn default_transcode_queue_timeout_secs() -> u64 {
+    30
+}
+
+fn default_read_ahead_chunks() -> usize {
+    2
+}
+
+fn default_segment_cache_max_mb() -> u64 {
+    4096
+}
+
+fn default_trickplay_interval_secs() -> u64 {
+    10
+}
+
+/// `403 Forbidden` unless `path`, with `..` and symlinks resolved, is within
+/// the series or movies root. The server listens on every interface, so
+/// `?path=` must not reach the rest of the disk.
+fn forbid_outside_library(path: &str) -> Option<Response> {
+    let config = load_config();
+    let inside = fs::canonicalize(path).is_ok_and(|file| {
+        [config.series_root, config.movies_root]
+            .iter()
+            .filter_map(|root| fs::canonicalize(root).ok())
+            .any(|root| file.starts_with(root))
+    });
+    (!inside)
+        .then(|| hls::error_response(StatusCode::FORBIDDEN, format!("Not a library file: {path}")))
+}
+
+/// Directory for caches that can be rebuilt at any time.
+pub fn cache_dir() -> PathBuf {
+    let project_dirs = directories::ProjectDirs::from("com", "dr42", "nexus").unwrap();
+    project_dirs.cache_dir().to_path_buf()
 }
 
 fn get_config_path() -> PathBuf {
@@ -55,6 +227,16 @@ pub fn load_config() -> Config {
         Config {
             series_root: video_dir.to_str().unwrap().to_string(),
             movies_root: video_dir.to_str().unwrap().to_string(),
+            encoder: None,
+            ffmpeg_path: default_ffmpeg_path(),
+            ffprobe_path: default_ffprobe_path(),
+            max_transcodes: default_max_transcodes(),
+            max_transcodes_per_user: default_max_transcodes_per_user(),
+            transcode_queue_timeout_secs: default_transcode_queue_timeout_secs(),
+            read_ahead_chunks: default_read_ahead_chunks(),
+            segment_cache_max_mb: default_segment_cache_max_mb(),
+            tesseract_path: None,
+            trickplay_interval_secs: default_trickplay_interval_secs(),
         }
     }
 }
@@ -71,6 +253,10 @@ pub async fn get_config() -> impl IntoResponse {
     Json(config)
 }
 
+pub async fn get_ffmpeg_info(Extension(ffmpeg): Extension<Arc<FfmpegInfo>>) -> impl IntoResponse {
+    Json(ffmpeg.as_ref().clone())
+}
+
 pub async fn update_config(Json(new_config): Json<Config>) -> impl IntoResponse {
     save_config(&new_config);
     StatusCode::OK
@@ -104,9 +290,24 @@ pub async fn browse(Query(params): Query<BrowseRequest>) -> impl IntoResponse {
     Json(entries)
 }
 
-pub async fn serve_video_metadata(Query(params): Query<VideoMetadataRequest>) -> impl IntoResponse {
+pub async fn serve_video_metadata(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Query(params): Query<VideoMetadataRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = forbid_outside_library(&params.path) {
+        return response;
+    }
     let input_path = params.path;
-    let video_metadata = video_helpers::get_video_metadata(&input_path).await;
+    let profile = client_profile(
+        params.session.as_deref(),
+        ProfileOverrides {
+            hdr: params.hdr,
+            audio_codec: params.audio_codec,
+            channels: params.channels,
+            normalization: params.normalization,
+        },
+    );
+    let video_metadata = video_helpers::get_video_metadata(&ffmpeg, &input_path, &profile).await;
     match video_metadata {
         Err(e) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -121,25 +322,77 @@ pub async fn serve_video_metadata(Query(params): Query<VideoMetadataRequest>) ->
     }
 }
 
-pub async fn serve_video(Query(params): Query<VideoRequest>) -> impl IntoResponse {
+pub async fn serve_video(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<VideoRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = forbid_outside_library(&params.path) {
+        return response;
+    }
     let input_path = params.path;
     println!("Input path: {input_path}");
 
-    let video_data = video_helpers::get_video_data(
+    let timestamp = params.timestamp.unwrap_or(0.0);
+    let user = transcode_manager::request_user(addr);
+    let profile = client_profile(
+        params.session.as_deref(),
+        ProfileOverrides {
+            hdr: params.hdr,
+            audio_codec: params.audio_codec,
+            channels: params.channels,
+            normalization: params.normalization,
+        },
+    );
+    let session = params
+        .session
+        .unwrap_or_else(|| format!("{user}:{input_path}"));
+    if let Some(quality) = params.quality.as_deref() {
+        if quality::find(quality).is_none() {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Unknown quality {quality}")))
+                .unwrap();
+        }
+    }
+    let version = params.protocol.unwrap_or(2);
+    if !framing::VERSIONS.contains(&version) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(format!("Unknown protocol version {version}")))
+            .unwrap();
+    }
+    let client = video_helpers::ClientOptions {
+        quality: params.quality,
+        profile,
+        framing: framing::Framing {
+            version,
+            checksums: params.checksums.unwrap_or(false),
+        },
+        burn_subtitle: params.burn_subtitle,
+    };
+    let video_data = read_ahead::video_chunk(
+        manager,
+        ffmpeg,
+        &user,
+        &session,
         &input_path,
-        params.timestamp.unwrap_or(0.0),
-        params.duration,
+        timestamp,
+        params.duration.unwrap_or(10.0),
+        &client,
     )
     .await;
     match video_data {
         Ok(data) => Response::builder()
-            .status(StatusCode::PARTIAL_CONTENT)
-            .body(Body::from(data.as_bytes().await))
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from_stream(data))
             .unwrap(),
-        Err(e) => {
+        Err((status, e)) => {
             println!("Video data error: {e}");
             Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .status(status)
                 .body(Body::from(format!("Video data error: {e}")))
                 .unwrap()
         }
@@ -155,7 +408,10 @@ struct FileData {
     file_size: u64,
 }
 
-pub async fn serve_file_list() -> impl IntoResponse {
+pub async fn serve_file_list(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+) -> impl IntoResponse {
     let config = load_config();
     let series_root = Path::new(&config.series_root);
     let movies_root = Path::new(&config.movies_root);
@@ -167,6 +423,16 @@ pub async fn serve_file_list() -> impl IntoResponse {
     all_files.extend_from_slice(&all_series_files);
     all_files.extend_from_slice(&all_movies_files);
 
+    // Probe the videos now so opening one does not have to wait for it, and
+    // make their seek bar thumbnails after.
+    let videos: Vec<String> = all_files
+        .iter()
+        .filter(|file| file.mime_type.starts_with("video/"))
+        .map(|file| file.file_path.clone())
+        .collect();
+    probe_cache::warm(ffmpeg.clone(), videos.clone());
+    trickplay::warm(ffmpeg, manager, videos, config.trickplay_interval_secs);
+
     let json_data = serde_json::to_string(&all_files).unwrap();
     Response::builder()
         .status(StatusCode::OK)
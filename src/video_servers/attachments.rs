@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock};
+use std::time::UNIX_EPOCH;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Extension, Path as UrlPath, Query},
+    http::status::StatusCode,
+    response::{IntoResponse, Response},
+};
+use fnv::FnvHasher;
+use hyper::header;
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, sync::Mutex};
+
+use super::device_profile::DeviceProfile;
+use super::ffmpeg::FfmpegInfo;
+use super::hls::error_response;
+use super::probe::{StreamInfo, StreamType};
+use super::transcode_manager::{request_user, TranscodeJob, TranscodeManager};
+use super::video_helpers;
+
+const FONT_EXTENSIONS: [&str; 5] = ["ttf", "otf", "ttc", "woff", "woff2"];
+
+/// A font attached to the file, e.g. for styled ASS subtitles.
+#[derive(Serialize, Debug, Clone)]
+pub struct Font {
+    /// Index among the file's attachments.
+    pub id: u64,
+    pub filename: String,
+    pub mimetype: Option<String>,
+}
+
+/// The font among the attachments of a file, if `stream` is one.
+pub fn font(stream: &StreamInfo, id: u64) -> Option<Font> {
+    if stream.codec_type != StreamType::Attachment {
+        return None;
+    }
+    let filename = stream.tags.filename()?.to_string();
+    let mimetype = stream.tags.mimetype().map(str::to_string);
+    let is_font = mimetype
+        .as_deref()
+        .is_some_and(|mimetype| mimetype.contains("font") || mimetype.contains("opentype"))
+        || Path::new(&filename)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| FONT_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+    is_font.then_some(Font {
+        id,
+        filename,
+        mimetype,
+    })
+}
+
+/// A lock per cache directory, so two requests for the same file do not
+/// write it at once while other files are extracted in parallel.
+static EXTRACTING: LazyLock<std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+fn extraction_lock(dir: &Path) -> Arc<Mutex<()>> {
+    let mut locks = EXTRACTING.lock().unwrap();
+    // Holders and waiters keep the lock alive, so one only the map refers to
+    // is free.
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    locks.entry(dir.to_path_buf()).or_default().clone()
+}
+
+/// Where the attachments of this version of the file go.
+fn cache_dir(path: &str) -> Option<PathBuf> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let mut hasher = FnvHasher::default();
+    path.hash(&mut hasher);
+    mtime.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    Some(
+        super::cache_dir()
+            .join("attachments")
+            .join(format!("{:016x}", hasher.finish())),
+    )
+}
+
+/// The directory of fonts of a file, if they have been extracted already.
+pub fn extracted_fonts_dir(path: &str) -> Option<PathBuf> {
+    cache_dir(path).filter(|dir| dir.exists())
+}
+
+/// A directory holding the fonts of a file, each named after its id. The
+/// fonts are extracted by a process of `job` the first time and kept until
+/// the file changes.
+pub async fn fonts_dir(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    path: &str,
+    fonts: &[Font],
+) -> Result<PathBuf, String> {
+    let dir = cache_dir(path).ok_or_else(|| format!("File not found: {path}"))?;
+    let lock = extraction_lock(&dir);
+    let _extracting = lock.lock().await;
+    if dir.exists() {
+        return Ok(dir);
+    }
+    // Extracted next to the final directory and renamed, so an interrupted
+    // extraction is never mistaken for a complete one.
+    let partial = dir.with_extension("partial");
+    fs::create_dir_all(&partial).map_err(|e| format!("Failed to create {partial:?}: {e}"))?;
+    let mut command = Command::new(&ffmpeg.ffmpeg_path);
+    command.args(["-v", "error", "-y"]);
+    for font in fonts {
+        command
+            .arg(format!("-dump_attachment:t:{}", font.id))
+            .arg(partial.join(font.id.to_string()));
+    }
+    // ffmpeg dumps the attachments while opening the input, then complains
+    // that there is no output, so only the files tell whether it worked.
+    command.args(["-i", path]).stderr(Stdio::null());
+    match job.spawn(command) {
+        Ok(process) => {
+            process.wait().await;
+        }
+        Err(e) => {
+            let _ = fs::remove_dir_all(&partial);
+            return Err(format!("Failed to execute ffmpeg: {e}"));
+        }
+    }
+    let missing: Vec<_> = fonts
+        .iter()
+        .filter(|font| !partial.join(font.id.to_string()).exists())
+        .map(|font| font.filename.as_str())
+        .collect();
+    if !missing.is_empty() {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(format!("Failed to extract {}", missing.join(", ")));
+    }
+    fs::rename(&partial, &dir).map_err(|e| format!("Failed to save fonts: {e}"))?;
+    println!("Extracted {} fonts of {path}", fonts.len());
+    Ok(dir)
+}
+
+#[derive(Deserialize)]
+pub struct FontRequest {
+    pub path: String,
+}
+
+/// `GET /fonts/{id}`: a font attached to the file, as listed in `fonts` of
+/// `/video-data`, for rendering its ASS subtitles on the client.
+pub async fn serve_font(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    UrlPath(id): UrlPath<u64>,
+    Query(params): Query<FontRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata =
+        match video_helpers::get_video_metadata(&ffmpeg, &params.path, &DeviceProfile::default())
+            .await
+        {
+            Ok(metadata) => metadata,
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        };
+    let Some(font) = metadata.fonts.iter().find(|font| font.id == id) else {
+        return error_response(StatusCode::NOT_FOUND, format!("No font {id}"));
+    };
+    let dir = match extracted_fonts_dir(&params.path) {
+        Some(dir) => Ok(dir),
+        None => {
+            let job = match manager
+                .start_segment_job(&request_user(addr), &params.path, 0.0)
+                .await
+            {
+                Ok(job) => job,
+                Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+            };
+            fonts_dir(&ffmpeg, &job, &params.path, &metadata.fonts).await
+        }
+    };
+    let data = match dir.and_then(|dir| {
+        fs::read(dir.join(id.to_string())).map_err(|e| format!("Failed to read font: {e}"))
+    }) {
+        Ok(data) => data,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            header::CONTENT_TYPE,
+            font.mimetype
+                .as_deref()
+                .unwrap_or("application/octet-stream"),
+        )
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("inline; filename=\"{}\"", font.filename.replace('"', "")),
+        )
+        .body(Body::from(data))
+        .unwrap()
+}
@@ -0,0 +1,176 @@
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use fnv::FnvHasher;
+
+/// Share of zero bytes in the high half of each character above which text
+/// without a BOM is taken to be UTF-16.
+const UTF16_ZEROS: f64 = 0.4;
+
+/// UTF-16 without a BOM, recognized by the zero high bytes of ASCII, which
+/// subtitles have plenty of in timestamps alone.
+fn utf16_without_bom(data: &[u8]) -> Option<&'static Encoding> {
+    let pairs = data.len() / 2;
+    if pairs < 8 {
+        return None;
+    }
+    let zeros = |offset: usize| {
+        data.chunks_exact(2)
+            .filter(|pair| pair[offset] == 0)
+            .count() as f64
+            / pairs as f64
+    };
+    if zeros(1) > UTF16_ZEROS && zeros(0) < UTF16_ZEROS / 4.0 {
+        Some(UTF_16LE)
+    } else if zeros(0) > UTF16_ZEROS && zeros(1) < UTF16_ZEROS / 4.0 {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+/// The encoding of subtitle text: from its BOM, UTF-16 or UTF-8 if it looks
+/// like it, otherwise guessed from the byte frequencies of the text.
+pub fn detect(data: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(data) {
+        return encoding;
+    }
+    if let Some(encoding) = utf16_without_bom(data) {
+        return encoding;
+    }
+    if std::str::from_utf8(data).is_ok() {
+        return UTF_8;
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(data, true);
+    detector.guess(None, true)
+}
+
+/// The encoding of a subtitle file, if it is not UTF-8.
+pub fn detect_file(path: &Path) -> Option<String> {
+    let data = fs::read(path).ok()?;
+    let encoding = detect(&data);
+    (encoding != UTF_8).then(|| encoding.name().to_string())
+}
+
+/// The encoding a WHATWG label, e.g. `latin1` or `cp1251`, stands for.
+pub fn encoding(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+}
+
+/// A file ffmpeg can read a subtitle file in `encoding` from: the file itself
+/// if it is UTF-8, else a UTF-8 copy kept in the cache directory until the
+/// file changes.
+pub fn utf8_file(path: &Path, encoding: &'static Encoding) -> Result<PathBuf, String> {
+    if encoding == UTF_8 {
+        return Ok(path.to_path_buf());
+    }
+    let metadata = fs::metadata(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_secs();
+    let mut hasher = FnvHasher::default();
+    path.hash(&mut hasher);
+    mtime.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    encoding.name().hash(&mut hasher);
+    // The extension tells ffmpeg the format.
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let dir = super::cache_dir().join("subtitles-utf8");
+    let file = dir.join(format!("{:016x}.{extension}", hasher.finish()));
+    if file.exists() {
+        return Ok(file);
+    }
+    let data = fs::read(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    // A BOM wins over `encoding`, and is dropped.
+    let (text, used, had_errors) = encoding.decode(&data);
+    if had_errors {
+        eprintln!(
+            "{path:?} is not valid {}; unknown characters replaced",
+            used.name()
+        );
+    }
+    // Written to a file of its own and renamed, so a request converting the
+    // same file at the same time never reads a partial copy.
+    fs::create_dir_all(&dir)
+        .and_then(|_| {
+            tempfile::Builder::new()
+                .suffix(".partial")
+                .tempfile_in(&dir)
+        })
+        .and_then(|mut temp| {
+            temp.write_all(text.as_bytes())?;
+            temp.persist(&file).map_err(|e| e.error)
+        })
+        .map_err(|e| format!("Failed to write {file:?}: {e}"))?;
+    println!("Converted {path:?} from {} to UTF-8", used.name());
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+
+    const CUE: &str = "1\n00:00:01,000 --> 00:00:02,500\nHello there\n";
+
+    fn utf16(text: &str, big_endian: bool) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|unit| {
+                if big_endian {
+                    unit.to_be_bytes()
+                } else {
+                    unit.to_le_bytes()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_bom_decides_the_encoding() {
+        let mut utf8 = b"\xef\xbb\xbf".to_vec();
+        utf8.extend_from_slice(CUE.as_bytes());
+        assert_eq!(detect(&utf8), UTF_8);
+
+        let mut le = vec![0xff, 0xfe];
+        le.extend(utf16(CUE, false));
+        assert_eq!(detect(&le), UTF_16LE);
+
+        let mut be = vec![0xfe, 0xff];
+        be.extend(utf16(CUE, true));
+        assert_eq!(detect(&be), UTF_16BE);
+    }
+
+    #[test]
+    fn utf16_is_recognized_without_a_bom() {
+        assert_eq!(detect(&utf16(CUE, false)), UTF_16LE);
+        assert_eq!(detect(&utf16(CUE, true)), UTF_16BE);
+        // Too short to tell.
+        assert_eq!(detect(&utf16("Hi", false)), UTF_8);
+    }
+
+    #[test]
+    fn valid_utf8_is_utf8() {
+        let text = format!("{CUE}Ça coûte très cher, déjà vu — «vraiment»\n");
+        assert_eq!(detect(text.as_bytes()), UTF_8);
+        assert_eq!(detect(CUE.as_bytes()), UTF_8);
+    }
+
+    #[test]
+    fn latin1_text_is_guessed_as_windows_1252() {
+        let text = format!(
+            "{CUE}\n2\n00:00:03,000 --> 00:00:05,000\nÇa coûte très cher, déjà vu.\nÀ la fenêtre, l'élève a été très étonné.\n"
+        );
+        let (data, _, had_errors) = WINDOWS_1252.encode(&text);
+        assert!(!had_errors);
+        assert_eq!(detect(&data), WINDOWS_1252);
+    }
+}
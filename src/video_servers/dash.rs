@@ -0,0 +1,512 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Extension, Query},
+    http::status::StatusCode,
+    response::{IntoResponse, Response},
+};
+use hyper::header;
+use serde::{Deserialize, Serialize};
+
+use super::device_profile::DeviceProfile;
+use super::encoders::{AudioCodec, ColorMode, Normalization};
+use super::ffmpeg::FfmpegInfo;
+use super::hls::{
+    error_response, query_string, segment_bounds, segment_count, stream_profile, SegmentRequest,
+    StreamKind, AUDIO_BANDWIDTH, SEGMENT_DURATION,
+};
+use super::probe::HdrFormat;
+use super::subtitles::{self, SubtitleFormat};
+use super::transcode_manager::{request_user, TranscodeJob, TranscodeManager};
+use super::video_helpers::{
+    self, Container, Tracktype, VideoMetadata, VideoOptions, DASH_SAMPLE_RATE,
+};
+
+#[derive(Deserialize)]
+pub struct ManifestRequest {
+    pub path: String,
+    /// Whether the client displays HDR; HDR sources are tone mapped if not.
+    pub hdr: Option<bool>,
+    /// Audio settings of audio streams, as for `/video`.
+    pub audio_codec: Option<AudioCodec>,
+    pub channels: Option<u32>,
+    pub normalization: Option<Normalization>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InitSegmentRequest {
+    pub path: String,
+    pub kind: StreamKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<u64>,
+    /// Quality profile of a video representation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdr: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_codec: Option<AudioCodec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalization: Option<Normalization>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubtitleRequest {
+    pub path: String,
+    pub track: u64,
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `SegmentTemplate` for one stream. The media URL keeps `$Number$` unescaped
+/// so the client can substitute the segment index.
+fn segment_template(stream: &InitSegmentRequest) -> String {
+    let init = query_string(stream);
+    format!(
+        "<SegmentTemplate timescale=\"1000\" duration=\"{}\" startNumber=\"0\" initialization=\"{}\" media=\"{}\"/>",
+        (SEGMENT_DURATION * 1000.0) as u64,
+        xml_escape(&format!("init.mp4?{init}")),
+        xml_escape(&format!("segment.m4s?{init}&index=$Number$")),
+    )
+}
+
+pub fn manifest(request: &ManifestRequest, metadata: &VideoMetadata) -> String {
+    let path = request.path.as_str();
+    let color = metadata.color_mode();
+    let video_codec = metadata.playback.video_target.codec_string(color);
+    let audio = metadata.segment_audio_options();
+    let mut mpd = String::new();
+    writeln!(mpd, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(
+        mpd,
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"static\" mediaPresentationDuration=\"PT{:.3}S\" minBufferTime=\"PT{SEGMENT_DURATION}S\">",
+        metadata.duration
+    )
+    .unwrap();
+    writeln!(mpd, "  <Period id=\"0\" start=\"PT0S\">").unwrap();
+
+    writeln!(
+        mpd,
+        "    <AdaptationSet id=\"0\" contentType=\"video\" mimeType=\"video/mp4\" segmentAlignment=\"true\">"
+    )
+    .unwrap();
+    if let ColorMode::Hdr(format) = color {
+        // ISO/IEC 23001-8 code points: BT.2020 primaries and matrix, and PQ
+        // or HLG transfer.
+        let transfer = if format == HdrFormat::Hlg { 18 } else { 16 };
+        for (property, value) in [
+            ("ColourPrimaries", 9),
+            ("TransferCharacteristics", transfer),
+            ("MatrixCoefficients", 9),
+        ] {
+            writeln!(
+                mpd,
+                "      <SupplementalProperty schemeIdUri=\"urn:mpeg:mpegB:cicp:{property}\" value=\"{value}\"/>"
+            )
+            .unwrap();
+        }
+    }
+    for rendition in &metadata.qualities {
+        writeln!(
+            mpd,
+            "      <Representation id=\"video-{}\" codecs=\"{video_codec}\" bandwidth=\"{}\" width=\"{}\" height=\"{}\">",
+            rendition.name,
+            rendition.max_bitrate as u64 * 1000,
+            rendition.width,
+            rendition.height
+        )
+        .unwrap();
+        writeln!(
+            mpd,
+            "        {}",
+            segment_template(&InitSegmentRequest {
+                path: path.to_string(),
+                kind: StreamKind::Video,
+                track: None,
+                quality: Some(rendition.name.to_string()),
+                hdr: request.hdr,
+                audio_codec: None,
+                channels: None,
+                normalization: None,
+            })
+        )
+        .unwrap();
+        writeln!(mpd, "      </Representation>").unwrap();
+    }
+    writeln!(mpd, "    </AdaptationSet>").unwrap();
+
+    let mut set_id = 1;
+    let mut first_audio = true;
+    for track in &metadata.tracks {
+        if track.kind != Tracktype::Audio {
+            continue;
+        }
+        writeln!(
+            mpd,
+            "    <AdaptationSet id=\"{set_id}\" contentType=\"audio\" mimeType=\"audio/mp4\" segmentAlignment=\"true\">"
+        )
+        .unwrap();
+        writeln!(mpd, "      <Label>{}</Label>", xml_escape(&track.label)).unwrap();
+        let role = if first_audio { "main" } else { "alternate" };
+        writeln!(
+            mpd,
+            "      <Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"{role}\"/>"
+        )
+        .unwrap();
+        writeln!(
+            mpd,
+            "      <Representation id=\"audio-{}\" codecs=\"{}\" bandwidth=\"{AUDIO_BANDWIDTH}\" audioSamplingRate=\"{DASH_SAMPLE_RATE}\">",
+            track.id,
+            audio.output_codec(Container::DashSegment).codec_string()
+        )
+        .unwrap();
+        writeln!(
+            mpd,
+            "        <AudioChannelConfiguration schemeIdUri=\"urn:mpeg:dash:23003:3:audio_channel_configuration:2011\" value=\"{}\"/>",
+            audio.output_channels(Container::DashSegment, track.channels)
+        )
+        .unwrap();
+        writeln!(
+            mpd,
+            "        {}",
+            segment_template(&InitSegmentRequest {
+                path: path.to_string(),
+                kind: StreamKind::Audio,
+                track: Some(track.id),
+                quality: None,
+                hdr: None,
+                audio_codec: request.audio_codec,
+                channels: request.channels,
+                normalization: request.normalization,
+            })
+        )
+        .unwrap();
+        writeln!(mpd, "      </Representation>").unwrap();
+        writeln!(mpd, "    </AdaptationSet>").unwrap();
+        set_id += 1;
+        first_audio = false;
+    }
+
+    for track in &metadata.tracks {
+        if !matches!(track.kind, Tracktype::Subtitle(_))
+            || metadata.unavailable_subs.contains(&track.id)
+        {
+            continue;
+        }
+        let url = format!(
+            "subtitle.vtt?{}",
+            query_string(&SubtitleRequest {
+                path: path.to_string(),
+                track: track.id,
+            })
+        );
+        writeln!(
+            mpd,
+            "    <AdaptationSet id=\"{set_id}\" contentType=\"text\" mimeType=\"text/vtt\">"
+        )
+        .unwrap();
+        writeln!(mpd, "      <Label>{}</Label>", xml_escape(&track.label)).unwrap();
+        writeln!(
+            mpd,
+            "      <Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"subtitle\"/>"
+        )
+        .unwrap();
+        writeln!(
+            mpd,
+            "      <Representation id=\"subtitle-{}\" bandwidth=\"256\">",
+            track.id
+        )
+        .unwrap();
+        writeln!(mpd, "        <BaseURL>{}</BaseURL>", xml_escape(&url)).unwrap();
+        writeln!(mpd, "      </Representation>").unwrap();
+        writeln!(mpd, "    </AdaptationSet>").unwrap();
+        set_id += 1;
+    }
+
+    writeln!(mpd, "  </Period>").unwrap();
+    writeln!(mpd, "</MPD>").unwrap();
+    mpd
+}
+
+/// Splits a fragmented MP4 into its initialization part (`ftyp` + `moov`)
+/// and the media fragments that follow.
+pub(super) fn split_init_segment(data: &[u8]) -> (&[u8], &[u8]) {
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        if &data[offset + 4..offset + 8] == b"moof" || size < 8 {
+            break;
+        }
+        offset += size;
+    }
+    data.split_at(offset.min(data.len()))
+}
+
+/// Transcodes one segment of a stream into DASH-ready fragmented MP4.
+#[allow(clippy::too_many_arguments)]
+async fn transcode_segment(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    path: &str,
+    kind: StreamKind,
+    track: Option<u64>,
+    metadata: &VideoMetadata,
+    video: VideoOptions,
+    start: f64,
+    length: f64,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let data = match (kind, track) {
+        (StreamKind::Video, _) => {
+            video_helpers::get_video(
+                ffmpeg,
+                job,
+                path,
+                start,
+                length,
+                Container::DashSegment,
+                &video,
+            )
+            .await
+        }
+        (StreamKind::Audio, Some(track)) => {
+            let Some(source) = metadata.audio_track(track) else {
+                return Err((StatusCode::NOT_FOUND, format!("No audio track {track}")));
+            };
+            video_helpers::get_audio(
+                ffmpeg,
+                job,
+                path,
+                track,
+                source.channels,
+                start,
+                length,
+                Container::DashSegment,
+                &metadata.segment_audio_options(),
+            )
+            .await
+        }
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Only audio and video streams have DASH segments".to_string(),
+            ))
+        }
+    };
+    data.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+fn mp4_response(data: Vec<u8>) -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "video/mp4")
+        .body(Body::from(data))
+        .unwrap()
+}
+
+pub async fn serve_manifest(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Query(params): Query<ManifestRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    match video_helpers::get_video_metadata(
+        &ffmpeg,
+        &params.path,
+        &stream_profile(
+            params.hdr,
+            params.audio_codec,
+            params.channels,
+            params.normalization,
+        ),
+    )
+    .await
+    {
+        Ok(metadata) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/dash+xml")
+            .body(Body::from(manifest(&params, &metadata)))
+            .unwrap(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+pub async fn serve_init_segment(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<InitSegmentRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata = match video_helpers::get_video_metadata(
+        &ffmpeg,
+        &params.path,
+        &stream_profile(
+            params.hdr,
+            params.audio_codec,
+            params.channels,
+            params.normalization,
+        ),
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    // Every segment is encoded with the same settings, so the header of the
+    // first one initializes all of them.
+    let (start, length) = segment_bounds(metadata.duration, 0);
+    let video = match metadata.segment_video_options(params.quality.as_deref()) {
+        Ok(video) => video,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let job = match manager
+        .start_segment_job(&request_user(addr), &params.path, start)
+        .await
+    {
+        Ok(job) => job,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    match transcode_segment(
+        &ffmpeg,
+        &job,
+        &params.path,
+        params.kind,
+        params.track,
+        &metadata,
+        video,
+        start,
+        length,
+    )
+    .await
+    {
+        Ok(data) => mp4_response(split_init_segment(&data).0.to_vec()),
+        Err((status, e)) => error_response(status, e),
+    }
+}
+
+pub async fn serve_media_segment(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<SegmentRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata = match video_helpers::get_video_metadata(
+        &ffmpeg,
+        &params.path,
+        &stream_profile(
+            params.hdr,
+            params.audio_codec,
+            params.channels,
+            params.normalization,
+        ),
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    if params.index >= segment_count(metadata.duration) {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("Segment {} is past the end of the file", params.index),
+        );
+    }
+    let (start, length) = segment_bounds(metadata.duration, params.index);
+    let video = match metadata.segment_video_options(params.quality.as_deref()) {
+        Ok(video) => video,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let job = match manager
+        .start_segment_job(&request_user(addr), &params.path, start)
+        .await
+    {
+        Ok(job) => job,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    match transcode_segment(
+        &ffmpeg,
+        &job,
+        &params.path,
+        params.kind,
+        params.track,
+        &metadata,
+        video,
+        start,
+        length,
+    )
+    .await
+    {
+        Ok(data) => mp4_response(split_init_segment(&data).1.to_vec()),
+        Err((status, e)) => error_response(status, e),
+    }
+}
+
+/// Whole subtitle track as a single WebVTT file, the sidecar form DASH
+/// clients expect for text adaptation sets.
+pub async fn serve_subtitle(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<SubtitleRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata =
+        match video_helpers::get_video_metadata(&ffmpeg, &params.path, &DeviceProfile::default())
+            .await
+        {
+            Ok(metadata) => metadata,
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        };
+    if metadata.subtitle_track(params.track).is_none() {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("No subtitle track {}", params.track),
+        );
+    }
+    let job = match manager
+        .start_segment_job(&request_user(addr), &params.path, 0.0)
+        .await
+    {
+        Ok(job) => job,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    let data = match subtitles::get_track(
+        &ffmpeg,
+        &job,
+        &params.path,
+        params.track,
+        metadata.sidecar_path(params.track),
+        SubtitleFormat::Vtt,
+        false,
+        0.0,
+    )
+    .await
+    {
+        Ok(data) => data,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/vtt")
+        .body(Body::from(data))
+        .unwrap()
+}
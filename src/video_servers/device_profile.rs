@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+
+use super::encoders::{AudioCodec, Normalization};
+
+/// What a client can play, sent by the player when it starts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct DeviceProfile {
+    /// ffprobe codec names, e.g. `h264`, `hevc`, `vp9`.
+    pub video_codecs: Vec<String>,
+    /// ffprobe codec names, e.g. `aac`, `opus`, `ac3`.
+    pub audio_codecs: Vec<String>,
+    /// Containers the client plays directly: `mp4`, `mkv` or `webm`.
+    pub containers: Vec<String>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Whether the display can show HDR. Otherwise HDR sources are tone mapped.
+    pub hdr: bool,
+    /// Codec audio is encoded to, and the only one copied. When unset, Opus
+    /// or AAC, whichever the client plays, and any supported codec is copied.
+    pub audio_codec: Option<AudioCodec>,
+    /// Most audio channels the client outputs. When unset, re-encoded audio
+    /// is downmixed to stereo.
+    pub audio_channels: Option<u32>,
+    pub normalization: Normalization,
+}
+
+/// What the server assumed of every client before profiles existed.
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        DeviceProfile {
+            video_codecs: vec!["h264".to_string(), "hevc".to_string()],
+            audio_codecs: vec!["aac".to_string(), "opus".to_string()],
+            containers: vec!["mp4".to_string()],
+            max_width: None,
+            max_height: None,
+            hdr: false,
+            audio_codec: None,
+            audio_channels: None,
+            normalization: Normalization::default(),
+        }
+    }
+}
+
+impl DeviceProfile {
+    pub fn supports_video(&self, codec: &str) -> bool {
+        self.video_codecs.iter().any(|supported| supported == codec)
+    }
+
+    pub fn supports_audio(&self, codec: &str) -> bool {
+        self.audio_codecs.iter().any(|supported| supported == codec)
+    }
+
+    /// Whether a file in one of `containers`, as named by
+    /// `SourceInfo::containers`, can be handed to the client as is.
+    pub fn supports_container(&self, containers: &[&str]) -> bool {
+        self.containers
+            .iter()
+            .any(|container| containers.contains(&container.as_str()))
+    }
+
+    /// Largest video size the client accepts.
+    pub fn max_size(&self) -> (u32, u32) {
+        (
+            self.max_width.unwrap_or(u32::MAX),
+            self.max_height.unwrap_or(u32::MAX),
+        )
+    }
+}
+
+/// Profiles are forgotten after this long without being used.
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+static SESSIONS: LazyLock<Mutex<HashMap<String, (DeviceProfile, Instant)>>> =
+    LazyLock::new(Default::default);
+
+/// The profile a player registered under `session`, or the default one.
+pub fn lookup(session: Option<&str>) -> DeviceProfile {
+    let Some(session) = session else {
+        return DeviceProfile::default();
+    };
+    let mut sessions = SESSIONS.lock().unwrap();
+    match sessions.get_mut(session) {
+        Some((profile, last_used)) => {
+            *last_used = Instant::now();
+            profile.clone()
+        }
+        None => DeviceProfile::default(),
+    }
+}
+
+fn new_session_id() -> String {
+    let hasher = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        hasher.hash_one(Instant::now()),
+        hasher.hash_one(std::process::id())
+    )
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    session: String,
+}
+
+/// `POST /video-session`: registers a device profile and returns the
+/// session id to pass to `/video-data` and `/video`.
+pub async fn create_session(Json(profile): Json<DeviceProfile>) -> impl IntoResponse {
+    let session = new_session_id();
+    println!("Device profile for session {session}: {profile:?}");
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, (_, last_used)| last_used.elapsed() < SESSION_TTL);
+    sessions.insert(session.clone(), (profile, Instant::now()));
+    Json(SessionResponse { session })
+}
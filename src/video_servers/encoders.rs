@@ -0,0 +1,363 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use super::probe::HdrFormat;
+
+/// The video encoder used for transcoding. Each backend owns the arguments
+/// for hardware decoding, the scale filter and the encoder itself, so the
+/// transcoder never has to special case a particular GPU vendor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EncoderBackend {
+    Libx264,
+    Libx265,
+    Vaapi,
+    Qsv,
+    Nvenc,
+}
+
+/// Video codecs the transcoder can produce.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+}
+
+impl VideoCodec {
+    /// ffprobe's name for the codec.
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::Hevc => "hevc",
+        }
+    }
+
+    /// RFC 6381 codec string of the encoded stream, for MSE `addSourceBuffer`.
+    pub fn codec_string(&self, color: ColorMode) -> &'static str {
+        match (self, color) {
+            (VideoCodec::H264, _) => "avc1.640028",
+            (VideoCodec::Hevc, ColorMode::Hdr(_)) => "hvc1.2.4.L153.B0",
+            (VideoCodec::Hevc, _) => "hvc1.1.6.L93.B0",
+        }
+    }
+}
+
+/// Audio codecs the transcoder can produce.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    #[default]
+    Opus,
+    Aac,
+    Ac3,
+    Eac3,
+    Flac,
+}
+
+impl AudioCodec {
+    /// ffprobe's name for the codec.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "opus",
+            AudioCodec::Aac => "aac",
+            AudioCodec::Ac3 => "ac3",
+            AudioCodec::Eac3 => "eac3",
+            AudioCodec::Flac => "flac",
+        }
+    }
+
+    pub fn encoder_name(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "libopus",
+            AudioCodec::Aac => "aac",
+            AudioCodec::Ac3 => "ac3",
+            AudioCodec::Eac3 => "eac3",
+            AudioCodec::Flac => "flac",
+        }
+    }
+
+    pub fn codec_string(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "opus",
+            AudioCodec::Aac => "mp4a.40.2",
+            AudioCodec::Ac3 => "ac-3",
+            AudioCodec::Eac3 => "ec-3",
+            AudioCodec::Flac => "flac",
+        }
+    }
+
+    /// Most channels ffmpeg's encoder for the codec accepts.
+    pub fn max_channels(&self) -> u32 {
+        match self {
+            AudioCodec::Ac3 | AudioCodec::Eac3 => 6,
+            AudioCodec::Opus | AudioCodec::Aac | AudioCodec::Flac => 8,
+        }
+    }
+}
+
+/// Loudness processing of re-encoded audio.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    Off,
+    /// EBU R128 loudness normalization.
+    #[default]
+    Loudnorm,
+    /// Compresses the dynamic range so quiet dialogue and loud effects end
+    /// up closer together, then normalizes.
+    Night,
+}
+
+impl Normalization {
+    pub fn filter(&self) -> Option<&'static str> {
+        match self {
+            Normalization::Off => None,
+            Normalization::Loudnorm => Some("loudnorm"),
+            Normalization::Night => {
+                Some("acompressor=threshold=0.05:ratio=6:attack=5:release=200:makeup=4,loudnorm")
+            }
+        }
+    }
+}
+
+/// How the transcoder treats the colors of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    /// SDR source, encoded as 8-bit BT.709.
+    #[default]
+    Sdr,
+    /// HDR source for a display that cannot show it, tone mapped to SDR.
+    Tonemap(HdrFormat),
+    /// HDR source kept as 10-bit HDR with its color metadata.
+    Hdr(HdrFormat),
+}
+
+/// zscale's name for the transfer function of an HDR format. Dolby Vision
+/// is never re-encoded, see `VideoMetadata::check_reencode`.
+fn transfer(format: HdrFormat) -> &'static str {
+    match format {
+        HdrFormat::Hlg => "arib-std-b67",
+        HdrFormat::Hdr10 | HdrFormat::DolbyVision => "smpte2084",
+    }
+}
+
+/// Software tone mapping to 8-bit BT.709, for frames in system memory.
+fn zscale_tonemap(format: HdrFormat) -> String {
+    format!(
+        "zscale=tin={}:pin=bt2020:min=bt2020nc:t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p",
+        transfer(format)
+    )
+}
+
+impl ColorMode {
+    /// Color metadata written to the output stream.
+    pub fn output_args(&self) -> Vec<String> {
+        let (primaries, transfer, matrix) = match self {
+            ColorMode::Sdr => return Vec::new(),
+            ColorMode::Tonemap(_) => ("bt709", "bt709", "bt709"),
+            ColorMode::Hdr(format) => ("bt2020", transfer(*format), "bt2020nc"),
+        };
+        [
+            "-color_primaries",
+            primaries,
+            "-color_trc",
+            transfer,
+            "-colorspace",
+            matrix,
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+    }
+}
+
+const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+const NVIDIA_DEVICE: &str = "/dev/nvidiactl";
+
+impl EncoderBackend {
+    /// Arguments placed before `-i` to set up hardware decoding.
+    pub fn decode_args(&self) -> Vec<String> {
+        let args: &[&str] = match self {
+            EncoderBackend::Libx264 | EncoderBackend::Libx265 => &[],
+            EncoderBackend::Vaapi => &[
+                "-hwaccel",
+                "vaapi",
+                "-hwaccel_output_format",
+                "vaapi",
+                "-vaapi_device",
+                VAAPI_DEVICE,
+            ],
+            EncoderBackend::Qsv => &["-hwaccel", "qsv", "-hwaccel_output_format", "qsv"],
+            EncoderBackend::Nvenc => &["-hwaccel", "cuda", "-hwaccel_output_format", "cuda"],
+        };
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Arguments placed before `-i` when frames are decoded on the CPU, e.g.
+    /// to draw subtitles on them, but still encoded by this backend.
+    pub fn software_decode_args(&self) -> Vec<String> {
+        match self {
+            EncoderBackend::Vaapi => vec!["-vaapi_device".to_string(), VAAPI_DEVICE.to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Like `video_filter`, for frames decoded on the CPU. The NVENC and QSV
+    /// encoders take system memory frames; VAAPI needs them uploaded.
+    pub fn software_filter(&self, width: u32, height: u32, color: ColorMode) -> String {
+        let filter = EncoderBackend::Libx264.video_filter(width, height, color);
+        match (self, color) {
+            (EncoderBackend::Vaapi, ColorMode::Hdr(_)) => format!("{filter},format=p010,hwupload"),
+            (EncoderBackend::Vaapi, _) => format!("{filter},format=nv12,hwupload"),
+            _ => filter,
+        }
+    }
+
+    /// The `-vf` filter scaling decoded frames to `width`x`height` and
+    /// converting them for the encoder: 8-bit for SDR, tone mapped for
+    /// `Tonemap` and 10-bit for `Hdr`.
+    pub fn video_filter(&self, width: u32, height: u32, color: ColorMode) -> String {
+        match (self, color) {
+            (EncoderBackend::Libx264 | EncoderBackend::Libx265, ColorMode::Sdr) => {
+                format!("scale={width}:{height},format=yuv420p")
+            }
+            (EncoderBackend::Libx264 | EncoderBackend::Libx265, ColorMode::Tonemap(format)) => {
+                format!("scale={width}:{height},{}", zscale_tonemap(format))
+            }
+            (EncoderBackend::Libx264 | EncoderBackend::Libx265, ColorMode::Hdr(_)) => {
+                format!("scale={width}:{height},format=yuv420p10le")
+            }
+            (EncoderBackend::Vaapi, ColorMode::Sdr) => {
+                format!("scale_vaapi=w={width}:h={height}:format=nv12")
+            }
+            (EncoderBackend::Vaapi, ColorMode::Tonemap(_)) => format!(
+                "tonemap_vaapi=format=nv12:p=bt709:t=bt709:m=bt709,scale_vaapi=w={width}:h={height}:format=nv12"
+            ),
+            (EncoderBackend::Vaapi, ColorMode::Hdr(_)) => {
+                format!("scale_vaapi=w={width}:h={height}:format=p010")
+            }
+            (EncoderBackend::Qsv, ColorMode::Sdr) => {
+                format!("scale_qsv=w={width}:h={height}:format=nv12")
+            }
+            (EncoderBackend::Qsv, ColorMode::Tonemap(_)) => {
+                format!("vpp_qsv=tonemap=1:format=nv12:w={width}:h={height}")
+            }
+            (EncoderBackend::Qsv, ColorMode::Hdr(_)) => {
+                format!("scale_qsv=w={width}:h={height}:format=p010")
+            }
+            (EncoderBackend::Nvenc, ColorMode::Sdr) => {
+                format!("scale_cuda={width}:{height}:format=yuv420p")
+            }
+            // There is no CUDA tone mapping filter in mainline ffmpeg, so the
+            // scaled frames are tone mapped on the CPU and uploaded again by
+            // the encoder.
+            (EncoderBackend::Nvenc, ColorMode::Tonemap(format)) => format!(
+                "scale_cuda={width}:{height}:format=p010,hwdownload,format=p010le,{}",
+                zscale_tonemap(format)
+            ),
+            (EncoderBackend::Nvenc, ColorMode::Hdr(_)) => {
+                format!("scale_cuda={width}:{height}:format=p010")
+            }
+        }
+    }
+
+    /// The codec this backend was chosen for.
+    pub fn native_codec(&self) -> VideoCodec {
+        match self {
+            EncoderBackend::Libx264 => VideoCodec::H264,
+            _ => VideoCodec::Hevc,
+        }
+    }
+
+    /// The ffmpeg encoder producing `codec` on this backend. The software
+    /// backends use libx264 and libx265 for either codec.
+    pub fn encoder_name_for(&self, codec: VideoCodec) -> &'static str {
+        match (self, codec) {
+            (EncoderBackend::Libx264 | EncoderBackend::Libx265, VideoCodec::H264) => "libx264",
+            (EncoderBackend::Libx264 | EncoderBackend::Libx265, VideoCodec::Hevc) => "libx265",
+            (EncoderBackend::Vaapi, VideoCodec::H264) => "h264_vaapi",
+            (EncoderBackend::Vaapi, VideoCodec::Hevc) => "hevc_vaapi",
+            (EncoderBackend::Qsv, VideoCodec::H264) => "h264_qsv",
+            (EncoderBackend::Qsv, VideoCodec::Hevc) => "hevc_qsv",
+            (EncoderBackend::Nvenc, VideoCodec::H264) => "h264_nvenc",
+            (EncoderBackend::Nvenc, VideoCodec::Hevc) => "hevc_nvenc",
+        }
+    }
+
+    /// Encoder selection and quality arguments, with the video bitrate
+    /// capped at `max_bitrate` kbit/s. The software encoders and NVENC keep
+    /// constant quality under the cap; VAAPI and QSV switch to capped VBR.
+    /// HDR output needs `codec` to be HEVC.
+    pub fn encode_args(
+        &self,
+        codec: VideoCodec,
+        max_bitrate: u32,
+        color: ColorMode,
+    ) -> Vec<String> {
+        let encoder = self.encoder_name_for(codec);
+        let maxrate = format!("{max_bitrate}k");
+        let bufsize = format!("{}k", max_bitrate * 2);
+        let cap = ["-maxrate", &maxrate, "-bufsize", &bufsize];
+        let rate_control: Vec<&str> = match self {
+            EncoderBackend::Libx264 | EncoderBackend::Libx265 => {
+                [&["-preset", "veryfast", "-crf", "20"][..], &cap].concat()
+            }
+            EncoderBackend::Vaapi => [&["-rc_mode", "VBR", "-b:v", &maxrate][..], &cap].concat(),
+            EncoderBackend::Qsv => [&["-b:v", &maxrate][..], &cap].concat(),
+            EncoderBackend::Nvenc => [&["-rc", "vbr", "-cq", "20", "-b:v", "0"][..], &cap].concat(),
+        };
+        let mut args: Vec<String> = ["-c:v", encoder]
+            .iter()
+            .chain(&rate_control)
+            .map(|s| s.to_string())
+            .collect();
+        if codec == VideoCodec::Hevc {
+            args.extend(["-tag:v".to_string(), "hvc1".to_string()]);
+        }
+        if let ColorMode::Hdr(_) = color {
+            args.extend(["-profile:v".to_string(), "main10".to_string()]);
+            if encoder == "libx265" {
+                args.extend([
+                    "-x265-params".to_string(),
+                    "hdr10-opt=1:repeat-headers=1".to_string(),
+                ]);
+            }
+        }
+        args
+    }
+
+    /// The ffmpeg encoder name this backend depends on.
+    pub fn encoder_name(&self) -> &'static str {
+        self.encoder_name_for(self.native_codec())
+    }
+
+    /// Whether the device node the backend needs is present on this machine.
+    pub fn device_present(&self) -> bool {
+        match self {
+            EncoderBackend::Libx264 | EncoderBackend::Libx265 => true,
+            EncoderBackend::Vaapi | EncoderBackend::Qsv => Path::new(VAAPI_DEVICE).exists(),
+            EncoderBackend::Nvenc => Path::new(NVIDIA_DEVICE).exists(),
+        }
+    }
+}
+
+/// Picks an encoder backend from the encoders ffmpeg was built with.
+/// Hardware backends are preferred over software, but only when their device
+/// node exists.
+pub fn detect_encoder(encoders: &[String]) -> EncoderBackend {
+    let candidates = [
+        EncoderBackend::Nvenc,
+        EncoderBackend::Qsv,
+        EncoderBackend::Vaapi,
+        EncoderBackend::Libx264,
+        EncoderBackend::Libx265,
+    ];
+    for backend in candidates {
+        let available = encoders.iter().any(|name| name == backend.encoder_name());
+        if backend.device_present() && available {
+            return backend;
+        }
+    }
+    EncoderBackend::Libx264
+}
@@ -0,0 +1,116 @@
+use serde::Serialize;
+use tokio::process::Command;
+
+use super::encoders::{self, EncoderBackend};
+use super::Config;
+
+/// What we learned about the ffmpeg installation at startup.
+#[derive(Serialize, Debug, Clone)]
+pub struct FfmpegInfo {
+    pub ffmpeg_path: String,
+    pub ffprobe_path: String,
+    pub ffmpeg_version: String,
+    pub ffprobe_version: String,
+    pub encoder: EncoderBackend,
+    pub encoders: Vec<String>,
+    pub decoders: Vec<String>,
+}
+
+/// Encoders the transcoder needs regardless of the video backend. Audio
+/// only needs AAC, which every client falls back to and HLS segments always
+/// use; other audio codecs are only picked when ffmpeg can encode them.
+const REQUIRED_ENCODERS: [&str; 2] = ["aac", "webvtt"];
+
+/// Runs `binary -version` and returns the version from its first line.
+async fn probe_version(binary: &str, config_key: &str) -> Result<String, String> {
+    let output = Command::new(binary)
+        .args(["-hide_banner", "-version"])
+        .output()
+        .await
+        .map_err(|e| {
+            format!(
+                "Could not run '{binary}': {e}. Install it or set `{config_key}` in the config."
+            )
+        })?;
+    if !output.status.success() {
+        return Err(format!(
+            "'{binary} -version' exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) ..."
+    stdout
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(2))
+        .map(|version| version.to_string())
+        .ok_or_else(|| format!("'{binary} -version' printed no version"))
+}
+
+/// Lists the codec names from `ffmpeg -encoders` or `ffmpeg -decoders`.
+async fn list_codecs(ffmpeg: &str, flag: &str) -> Result<Vec<String>, String> {
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", flag])
+        .output()
+        .await
+        .map_err(|e| format!("Could not run '{ffmpeg} {flag}': {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "'{ffmpeg} {flag}' exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The listing starts with a legend terminated by " ------", then one
+    // codec per line as "<flags> <name> <description>".
+    let codecs = stdout
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|name| name.to_string())
+        .collect();
+    Ok(codecs)
+}
+
+/// Validates the configured ffmpeg and ffprobe binaries and picks the encoder
+/// backend. Any problem is returned as a diagnostic meant for the operator.
+pub async fn check_ffmpeg(config: &Config) -> Result<FfmpegInfo, String> {
+    let ffmpeg_version = probe_version(&config.ffmpeg_path, "ffmpeg_path").await?;
+    let ffprobe_version = probe_version(&config.ffprobe_path, "ffprobe_path").await?;
+    let encoders = list_codecs(&config.ffmpeg_path, "-encoders").await?;
+    let decoders = list_codecs(&config.ffmpeg_path, "-decoders").await?;
+
+    let encoder = match config.encoder {
+        Some(encoder) => encoder,
+        None => encoders::detect_encoder(&encoders),
+    };
+
+    let mut missing: Vec<&str> = REQUIRED_ENCODERS
+        .into_iter()
+        .filter(|name| !encoders.iter().any(|encoder| encoder == name))
+        .collect();
+    if !encoders.iter().any(|name| name == encoder.encoder_name()) {
+        missing.push(encoder.encoder_name());
+    }
+    if !missing.is_empty() {
+        return Err(format!(
+            "'{}' (version {ffmpeg_version}) lacks required encoders: {}",
+            config.ffmpeg_path,
+            missing.join(", ")
+        ));
+    }
+
+    Ok(FfmpegInfo {
+        ffmpeg_path: config.ffmpeg_path.clone(),
+        ffprobe_path: config.ffprobe_path.clone(),
+        ffmpeg_version,
+        ffprobe_version,
+        encoder,
+        encoders,
+        decoders,
+    })
+}
@@ -0,0 +1,257 @@
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use futures::{future, stream, StreamExt, TryStreamExt};
+
+use super::video_helpers::ByteStream;
+
+// NOTE: Version 1 of a `/video` response is
+// [
+//     u32 -> number of audio tracks,
+//     u32 -> number of subtitle tracks,
+//     -- For the video track, then each audio track, then each subtitle track --
+//     u64 -> track id,
+//     -- Repeated until the track is complete --
+//     u32 -> chunk length (0 terminates the track),
+//     Vec<u8> -> chunk data,
+//     --
+//     --
+// ]
+// so the server can forward ffmpeg output without knowing its length.
+//
+// Version 2 starts with a header describing every track:
+// [
+//     [u8; 4] -> magic "NXFV",
+//     u16 -> version (2),
+//     u8 -> flags (bit 0: every track ends with a checksum),
+//     u8 -> reserved (0),
+//     f64 -> start PTS of every track in seconds,
+//     u32 -> number of tracks,
+//     -- For each track --
+//     u64 -> track id,
+//     u8 -> kind (0 video, 1 audio, 2 subtitle),
+//     u8 -> codec length, then the RFC 6381 codec string,
+//     u8 -> language length, then the language (empty if unknown),
+//     --
+// ]
+// followed by the tracks in header order, each as length-prefixed chunks
+// terminated by a 0 length, then the CRC-32 of its data if checksums are on.
+// All integers are little-endian.
+
+const MAGIC: &[u8; 4] = b"NXFV";
+
+/// Versions `/video` can frame its response in.
+pub const VERSIONS: [u16; 2] = [1, 2];
+
+/// How a `/video` response is framed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    pub version: u16,
+    /// Version 2 only: end every track with the CRC-32 of its data.
+    pub checksums: bool,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            version: 2,
+            checksums: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Video = 0,
+    Audio = 1,
+    Subtitle = 2,
+}
+
+/// What the version 2 header says about a track.
+#[derive(Debug, Clone)]
+pub struct TrackHeader {
+    pub id: u64,
+    pub kind: TrackKind,
+    pub codec: String,
+    pub language: Option<String>,
+}
+
+/// Writes a string prefixed with its length, cut to the 255 bytes a `u8`
+/// length allows.
+fn push_short_string(buffer: &mut Vec<u8>, value: &str) {
+    let mut end = value.len().min(u8::MAX as usize);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    buffer.push(end as u8);
+    buffer.extend_from_slice(&value.as_bytes()[..end]);
+}
+
+impl Framing {
+    /// Everything before the first track. `tracks` are in the order their
+    /// data follows: video, then audio, then subtitles.
+    pub fn header(&self, start: f64, tracks: &[TrackHeader]) -> Bytes {
+        let mut header = Vec::new();
+        if self.version == 1 {
+            let count = |kind| tracks.iter().filter(|track| track.kind == kind).count() as u32;
+            header.extend_from_slice(&count(TrackKind::Audio).to_le_bytes());
+            header.extend_from_slice(&count(TrackKind::Subtitle).to_le_bytes());
+            return Bytes::from(header);
+        }
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.version.to_le_bytes());
+        header.push(self.checksums as u8);
+        header.push(0);
+        header.extend_from_slice(&start.to_le_bytes());
+        header.extend_from_slice(&(tracks.len() as u32).to_le_bytes());
+        for track in tracks {
+            header.extend_from_slice(&track.id.to_le_bytes());
+            header.push(track.kind as u8);
+            push_short_string(&mut header, &track.codec);
+            push_short_string(&mut header, track.language.as_deref().unwrap_or_default());
+        }
+        Bytes::from(header)
+    }
+
+    /// Frames the data of one track, forwarding chunks as they arrive. Empty
+    /// chunks are dropped, as their zero length would end the track.
+    pub fn track(&self, id: u64, data: ByteStream) -> ByteStream {
+        let crc = Arc::new(Mutex::new(Crc32::default()));
+        let tee = crc.clone();
+        let checksums = self.checksums && self.version >= 2;
+        let chunks = data
+            .try_filter(|chunk| future::ready(!chunk.is_empty()))
+            .map_ok(move |chunk| {
+                if checksums {
+                    tee.lock().unwrap().update(&chunk);
+                }
+                let length = Bytes::copy_from_slice(&(chunk.len() as u32).to_le_bytes());
+                stream::iter([Ok(length), Ok(chunk)])
+            })
+            .try_flatten();
+        let end = stream::once(future::ready(Ok(Bytes::from_static(&[0; 4]))));
+        let track = chunks.chain(end);
+        let track = if self.version == 1 {
+            let id = stream::once(future::ready(Ok(Bytes::copy_from_slice(&id.to_le_bytes()))));
+            id.chain(track).boxed()
+        } else {
+            track.boxed()
+        };
+        if !checksums {
+            return track;
+        }
+        // Evaluated lazily, after every chunk has gone through.
+        let checksum = stream::once(async move {
+            let value = crc.lock().unwrap().finish();
+            Ok(Bytes::copy_from_slice(&value.to_le_bytes()))
+        });
+        track.chain(checksum).boxed()
+    }
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 == 1 {
+                (value >> 1) ^ 0xedb8_8320
+            } else {
+                value >> 1
+            };
+            bit += 1;
+        }
+        table[index] = value;
+        index += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 as used by zlib and PNG.
+#[derive(Default)]
+struct Crc32(u32);
+
+impl Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        let mut crc = !self.0;
+        for &byte in data {
+            crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.0 = !crc;
+    }
+
+    fn finish(&self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_known_values() {
+        assert_eq!(CRC32_TABLE[1], 0x7707_3096);
+        assert_eq!(CRC32_TABLE[255], 0x2d02_ef8d);
+        let mut crc = Crc32::default();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+
+        let mut split = Crc32::default();
+        split.update(b"1234");
+        split.update(b"56789");
+        assert_eq!(split.finish(), crc.finish());
+    }
+
+    #[tokio::test]
+    async fn track_ends_with_terminator_and_checksum() {
+        let framing = Framing {
+            version: 2,
+            checksums: true,
+        };
+        let chunks = [Bytes::from_static(b"1234"), Bytes::from_static(b"56789")];
+        let data = stream::iter(chunks.map(Ok)).boxed();
+        let framed: Vec<Bytes> = framing.track(7, data).try_collect().await.unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&4u32.to_le_bytes());
+        expected.extend_from_slice(b"1234");
+        expected.extend_from_slice(&5u32.to_le_bytes());
+        expected.extend_from_slice(b"56789");
+        expected.extend_from_slice(&0u32.to_le_bytes());
+        expected.extend_from_slice(&0xcbf4_3926u32.to_le_bytes());
+        assert_eq!(framed.concat(), expected);
+    }
+
+    #[tokio::test]
+    async fn empty_chunks_do_not_end_the_track() {
+        let framing = Framing {
+            version: 2,
+            checksums: false,
+        };
+        let chunks = [
+            Bytes::new(),
+            Bytes::from_static(b"12"),
+            Bytes::new(),
+            Bytes::from_static(b"345"),
+        ];
+        let data = stream::iter(chunks.map(Ok)).boxed();
+        let framed: Vec<Bytes> = framing.track(7, data).try_collect().await.unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&2u32.to_le_bytes());
+        expected.extend_from_slice(b"12");
+        expected.extend_from_slice(&3u32.to_le_bytes());
+        expected.extend_from_slice(b"345");
+        expected.extend_from_slice(&0u32.to_le_bytes());
+        assert_eq!(framed.concat(), expected);
+
+        let empty = stream::iter([Ok(Bytes::new())]).boxed();
+        let framed: Vec<Bytes> = framing.track(7, empty).try_collect().await.unwrap();
+        assert_eq!(framed.concat(), 0u32.to_le_bytes());
+    }
+}
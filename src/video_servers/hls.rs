@@ -0,0 +1,677 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Extension, Query},
+    http::status::StatusCode,
+    response::{IntoResponse, Response},
+};
+use hyper::header;
+use serde::{Deserialize, Serialize};
+
+use super::dash::split_init_segment;
+use super::device_profile::DeviceProfile;
+use super::encoders::{AudioCodec, ColorMode, Normalization, VideoCodec};
+use super::ffmpeg::FfmpegInfo;
+use super::probe::HdrFormat;
+use super::transcode_manager::{request_user, TranscodeManager};
+use super::video_helpers::{self, Container, Tracktype, VideoMetadata};
+
+/// Length of every HLS and DASH segment except the last one, in seconds.
+pub(super) const SEGMENT_DURATION: f64 = 6.0;
+/// Advertised bandwidth of an audio rendition, added to each variant.
+pub(super) const AUDIO_BANDWIDTH: u64 = 128_000;
+
+const PLAYLIST_MIME: &str = "application/vnd.apple.mpegurl";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+#[derive(Deserialize)]
+pub struct MasterPlaylistRequest {
+    pub path: String,
+    /// Whether the client displays HDR; HDR sources are tone mapped if not.
+    pub hdr: Option<bool>,
+    /// Audio settings of audio streams, as for `/video`.
+    pub audio_codec: Option<AudioCodec>,
+    pub channels: Option<u32>,
+    pub normalization: Option<Normalization>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MediaPlaylistRequest {
+    pub path: String,
+    pub kind: StreamKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<u64>,
+    /// Quality profile of a video playlist.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdr: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_codec: Option<AudioCodec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalization: Option<Normalization>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SegmentRequest {
+    pub path: String,
+    pub kind: StreamKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdr: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_codec: Option<AudioCodec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalization: Option<Normalization>,
+    pub index: u64,
+}
+
+/// The default profile, for HLS and DASH clients, which pick among the
+/// renditions themselves, with the request's overrides applied.
+pub(super) fn stream_profile(
+    hdr: Option<bool>,
+    audio_codec: Option<AudioCodec>,
+    channels: Option<u32>,
+    normalization: Option<Normalization>,
+) -> DeviceProfile {
+    super::client_profile(
+        None,
+        super::ProfileOverrides {
+            hdr,
+            audio_codec,
+            channels,
+            normalization,
+        },
+    )
+}
+
+pub(super) fn query_string<T: Serialize>(query: &T) -> String {
+    serde_urlencoded::to_string(query).unwrap()
+}
+
+/// Attribute values in playlists are quoted strings and may not contain quotes.
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace(['"', '\n', '\r'], "'"))
+}
+
+pub(super) fn segment_count(duration: f64) -> u64 {
+    (duration / SEGMENT_DURATION).ceil().max(1.0) as u64
+}
+
+/// Start time and length of the segment at `index`.
+pub(super) fn segment_bounds(duration: f64, index: u64) -> (f64, f64) {
+    let start = index as f64 * SEGMENT_DURATION;
+    (start, SEGMENT_DURATION.min(duration - start))
+}
+
+/// Whether video segments are fragmented MP4 instead of MPEG-TS: players
+/// only take HEVC, and so HDR, from fMP4 segments with an `EXT-X-MAP`.
+fn fragmented_video(metadata: &VideoMetadata) -> bool {
+    metadata.playback.video_target == VideoCodec::Hevc
+}
+
+/// Playlist version: 7 for fMP4 segments, else 3.
+fn playlist_version(fragmented: bool) -> u32 {
+    if fragmented {
+        7
+    } else {
+        3
+    }
+}
+
+pub fn master_playlist(request: &MasterPlaylistRequest, metadata: &VideoMetadata) -> String {
+    let path = request.path.as_str();
+    let color = metadata.color_mode();
+    let audio = metadata.segment_audio_options();
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:{}\n",
+        playlist_version(fragmented_video(metadata))
+    );
+
+    let mut has_audio = false;
+    for track in &metadata.tracks {
+        if track.kind != Tracktype::Audio {
+            continue;
+        }
+        let uri = format!(
+            "media.m3u8?{}",
+            query_string(&MediaPlaylistRequest {
+                path: path.to_string(),
+                kind: StreamKind::Audio,
+                track: Some(track.id),
+                quality: None,
+                hdr: None,
+                audio_codec: request.audio_codec,
+                channels: request.channels,
+                normalization: request.normalization,
+            })
+        );
+        let default = if has_audio { "NO" } else { "YES" };
+        writeln!(
+            playlist,
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME={},DEFAULT={default},AUTOSELECT=YES,CHANNELS=\"{}\",URI={}",
+            quoted(&track.label),
+            audio.output_channels(Container::MpegTs, track.channels),
+            quoted(&uri)
+        )
+        .unwrap();
+        has_audio = true;
+    }
+
+    let mut has_subtitles = false;
+    for track in &metadata.tracks {
+        if !matches!(track.kind, Tracktype::Subtitle(_))
+            || metadata.unavailable_subs.contains(&track.id)
+        {
+            continue;
+        }
+        let uri = format!(
+            "media.m3u8?{}",
+            query_string(&MediaPlaylistRequest {
+                path: path.to_string(),
+                kind: StreamKind::Subtitle,
+                track: Some(track.id),
+                quality: None,
+                hdr: None,
+                audio_codec: None,
+                channels: None,
+                normalization: None,
+            })
+        );
+        writeln!(
+            playlist,
+            "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME={},DEFAULT=NO,AUTOSELECT=NO,URI={}",
+            quoted(&track.label),
+            quoted(&uri)
+        )
+        .unwrap();
+        has_subtitles = true;
+    }
+
+    let mut codecs = metadata
+        .playback
+        .video_target
+        .codec_string(color)
+        .to_string();
+    let mut groups = match color {
+        ColorMode::Hdr(HdrFormat::Hlg) => ",VIDEO-RANGE=HLG".to_string(),
+        ColorMode::Hdr(_) => ",VIDEO-RANGE=PQ".to_string(),
+        ColorMode::Sdr | ColorMode::Tonemap(_) => String::new(),
+    };
+    if has_audio {
+        codecs.push(',');
+        codecs.push_str(audio.output_codec(Container::MpegTs).codec_string());
+        groups.push_str(",AUDIO=\"audio\"");
+    }
+    if has_subtitles {
+        groups.push_str(",SUBTITLES=\"subs\"");
+    }
+    // One variant per rung of the ladder, largest first.
+    for rendition in metadata.qualities.iter().rev() {
+        let bandwidth = rendition.max_bitrate as u64 * 1000 + AUDIO_BANDWIDTH;
+        writeln!(
+            playlist,
+            "#EXT-X-STREAM-INF:BANDWIDTH={bandwidth},RESOLUTION={}x{}{groups},CODECS={}",
+            rendition.width,
+            rendition.height,
+            quoted(&codecs)
+        )
+        .unwrap();
+        writeln!(
+            playlist,
+            "media.m3u8?{}",
+            query_string(&MediaPlaylistRequest {
+                path: path.to_string(),
+                kind: StreamKind::Video,
+                track: None,
+                quality: Some(rendition.name.to_string()),
+                hdr: request.hdr,
+                audio_codec: None,
+                channels: None,
+                normalization: None,
+            })
+        )
+        .unwrap();
+    }
+    playlist
+}
+
+/// Playlist of a stream; `fragmented` video segments are fMP4 that start
+/// with the init segment named by `EXT-X-MAP`.
+pub fn media_playlist(request: &MediaPlaylistRequest, duration: f64, fragmented: bool) -> String {
+    let fragmented = fragmented && request.kind == StreamKind::Video;
+    let extension = match request.kind {
+        StreamKind::Subtitle => "vtt",
+        StreamKind::Video if fragmented => "m4s",
+        StreamKind::Video | StreamKind::Audio => "ts",
+    };
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+        playlist_version(fragmented),
+        SEGMENT_DURATION.ceil() as u64
+    );
+    if fragmented {
+        let init = format!("init.mp4?{}", query_string(request));
+        writeln!(playlist, "#EXT-X-MAP:URI={}", quoted(&init)).unwrap();
+    }
+    for index in 0..segment_count(duration) {
+        let (_, length) = segment_bounds(duration, index);
+        let uri = query_string(&SegmentRequest {
+            path: request.path.clone(),
+            kind: request.kind,
+            track: request.track,
+            quality: request.quality.clone(),
+            hdr: request.hdr,
+            audio_codec: request.audio_codec,
+            channels: request.channels,
+            normalization: request.normalization,
+            index,
+        });
+        writeln!(playlist, "#EXTINF:{length:.3},\nsegment.{extension}?{uri}").unwrap();
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+pub(super) fn error_response(status: StatusCode, message: String) -> Response {
+    println!("Streaming error: {message}");
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn playlist_response(playlist: String) -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, PLAYLIST_MIME)
+        .body(Body::from(playlist))
+        .unwrap()
+}
+
+pub async fn serve_master_playlist(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Query(params): Query<MasterPlaylistRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    match video_helpers::get_video_metadata(
+        &ffmpeg,
+        &params.path,
+        &stream_profile(
+            params.hdr,
+            params.audio_codec,
+            params.channels,
+            params.normalization,
+        ),
+    )
+    .await
+    {
+        Ok(metadata) => playlist_response(master_playlist(&params, &metadata)),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+pub async fn serve_media_playlist(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Query(params): Query<MediaPlaylistRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    match video_helpers::get_video_metadata(
+        &ffmpeg,
+        &params.path,
+        &stream_profile(
+            params.hdr,
+            params.audio_codec,
+            params.channels,
+            params.normalization,
+        ),
+    )
+    .await
+    {
+        Ok(metadata) => playlist_response(media_playlist(
+            &params,
+            metadata.duration,
+            fragmented_video(&metadata),
+        )),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+pub async fn serve_segment(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<SegmentRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata = match video_helpers::get_video_metadata(
+        &ffmpeg,
+        &params.path,
+        &stream_profile(
+            params.hdr,
+            params.audio_codec,
+            params.channels,
+            params.normalization,
+        ),
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    if params.index >= segment_count(metadata.duration) {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("Segment {} is past the end of the file", params.index),
+        );
+    }
+    let (start, length) = segment_bounds(metadata.duration, params.index);
+    let user = request_user(addr);
+    let job = match manager.start_segment_job(&user, &params.path, start).await {
+        Ok(job) => job,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+
+    let (content_type, body) = match (params.kind, params.track) {
+        (StreamKind::Video, _) => {
+            let options = match metadata.segment_video_options(params.quality.as_deref()) {
+                Ok(options) => options,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+            };
+            let fragmented = fragmented_video(&metadata);
+            let container = if fragmented {
+                Container::DashSegment
+            } else {
+                Container::MpegTs
+            };
+            let data = match video_helpers::get_video(
+                &ffmpeg,
+                &job,
+                &params.path,
+                start,
+                length,
+                container,
+                &options,
+            )
+            .await
+            {
+                Ok(data) => data,
+                Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            };
+            if fragmented {
+                (
+                    "video/mp4",
+                    Body::from(split_init_segment(&data).1.to_vec()),
+                )
+            } else {
+                ("video/mp2t", Body::from(data))
+            }
+        }
+        (StreamKind::Audio, Some(track)) => {
+            let Some(channels) = metadata.audio_track(track).map(|audio| audio.channels) else {
+                return error_response(StatusCode::NOT_FOUND, format!("No audio track {track}"));
+            };
+            let audio = match video_helpers::get_audio(
+                &ffmpeg,
+                &job,
+                &params.path,
+                track,
+                channels,
+                start,
+                length,
+                Container::MpegTs,
+                &metadata.segment_audio_options(),
+            )
+            .await
+            {
+                Ok(data) => data,
+                Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            };
+            ("video/mp2t", Body::from(audio))
+        }
+        (StreamKind::Subtitle, Some(track)) => {
+            if metadata.subtitle_track(track).is_none() {
+                return error_response(StatusCode::NOT_FOUND, format!("No subtitle track {track}"));
+            }
+            let subtitle = match video_helpers::get_subtitle(
+                &ffmpeg,
+                &job,
+                &params.path,
+                track,
+                metadata.sidecar_path(track),
+                start,
+                length,
+            )
+            .await
+            {
+                Ok(data) => data,
+                Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            };
+            ("text/vtt", Body::from(with_timestamp_map(&subtitle.data)))
+        }
+        (_, None) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "Audio and subtitle segments need a track".to_string(),
+            )
+        }
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
+        .unwrap()
+}
+
+/// Init segment of fMP4 video playlists, the header of the first segment.
+pub async fn serve_init_segment(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<MediaPlaylistRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata = match video_helpers::get_video_metadata(
+        &ffmpeg,
+        &params.path,
+        &stream_profile(
+            params.hdr,
+            params.audio_codec,
+            params.channels,
+            params.normalization,
+        ),
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    if params.kind != StreamKind::Video || !fragmented_video(&metadata) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Only fMP4 video playlists have an init segment".to_string(),
+        );
+    }
+    let options = match metadata.segment_video_options(params.quality.as_deref()) {
+        Ok(options) => options,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let (start, length) = segment_bounds(metadata.duration, 0);
+    let job = match manager
+        .start_segment_job(&request_user(addr), &params.path, start)
+        .await
+    {
+        Ok(job) => job,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    match video_helpers::get_video(
+        &ffmpeg,
+        &job,
+        &params.path,
+        start,
+        length,
+        Container::DashSegment,
+        &options,
+    )
+    .await
+    {
+        Ok(data) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "video/mp4")
+            .body(Body::from(split_init_segment(&data).0.to_vec()))
+            .unwrap(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// The transport stream segments start at PTS 0 for t=0 (no mux delay), and
+/// the cues already carry absolute times, so the mapping is the identity.
+fn with_timestamp_map(webvtt: &str) -> String {
+    let cues = webvtt
+        .strip_prefix('\u{feff}')
+        .unwrap_or(webvtt)
+        .trim_start()
+        .strip_prefix("WEBVTT")
+        .unwrap_or("")
+        .trim_start_matches(|c| c != '\n')
+        .trim_start();
+    format!("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:0,LOCAL:00:00:00.000\n\n{cues}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video_servers::device_profile::DeviceProfile;
+    use crate::video_servers::playback::{PlaybackDecision, PlaybackMode};
+    use crate::video_servers::probe::MediaInfo;
+    use crate::video_servers::quality::Rendition;
+
+    fn metadata(video_target: VideoCodec) -> VideoMetadata {
+        VideoMetadata {
+            duration: 15.0,
+            tracks: Vec::new(),
+            unavailable_subs: Vec::new(),
+            container: "matroska,webm".to_string(),
+            playback: PlaybackDecision {
+                mode: PlaybackMode::Transcode,
+                reason: String::new(),
+                copy_audio: false,
+                video_target,
+                audio_target: AudioCodec::Aac,
+            },
+            video_codec: String::new(),
+            audio_codec: String::new(),
+            source: Arc::new(MediaInfo::default()),
+            qualities: vec![Rendition {
+                name: "1080p",
+                width: 1920,
+                height: 1080,
+                max_bitrate: 8000,
+                scaled: false,
+            }],
+            device_profile: DeviceProfile::default(),
+            fonts: Vec::new(),
+            sidecars: Vec::new(),
+        }
+    }
+
+    fn master_request() -> MasterPlaylistRequest {
+        MasterPlaylistRequest {
+            path: "movie.mkv".to_string(),
+            hdr: None,
+            audio_codec: None,
+            channels: None,
+            normalization: None,
+        }
+    }
+
+    fn video_request() -> MediaPlaylistRequest {
+        MediaPlaylistRequest {
+            path: "movie.mkv".to_string(),
+            kind: StreamKind::Video,
+            track: None,
+            quality: Some("1080p".to_string()),
+            hdr: None,
+            audio_codec: None,
+            channels: None,
+            normalization: None,
+        }
+    }
+
+    #[test]
+    fn hevc_video_is_packaged_as_fmp4() {
+        let metadata = metadata(VideoCodec::Hevc);
+        let master = master_playlist(&master_request(), &metadata);
+        assert!(master.contains("#EXT-X-VERSION:7\n"));
+        assert!(master.contains("CODECS=\"hvc1.1.6.L93.B0\""));
+
+        let media = media_playlist(
+            &video_request(),
+            metadata.duration,
+            fragmented_video(&metadata),
+        );
+        assert!(media.contains("#EXT-X-VERSION:7\n"));
+        assert!(
+            media.contains("#EXT-X-MAP:URI=\"init.mp4?path=movie.mkv&kind=video&quality=1080p\"\n")
+        );
+        assert_eq!(media.matches("\nsegment.m4s?").count(), 3);
+        assert!(!media.contains("segment.ts"));
+    }
+
+    #[test]
+    fn h264_video_stays_in_transport_stream_segments() {
+        let metadata = metadata(VideoCodec::H264);
+        let master = master_playlist(&master_request(), &metadata);
+        assert!(master.contains("#EXT-X-VERSION:3\n"));
+        assert!(master.contains("CODECS=\"avc1.640028\""));
+
+        let media = media_playlist(
+            &video_request(),
+            metadata.duration,
+            fragmented_video(&metadata),
+        );
+        assert!(!media.contains("#EXT-X-MAP"));
+        assert_eq!(media.matches("\nsegment.ts?").count(), 3);
+    }
+
+    #[test]
+    fn audio_of_hevc_video_stays_in_transport_stream_segments() {
+        let request = MediaPlaylistRequest {
+            kind: StreamKind::Audio,
+            track: Some(1),
+            quality: None,
+            ..video_request()
+        };
+        let media = media_playlist(&request, 15.0, true);
+        assert!(media.contains("#EXT-X-VERSION:3\n"));
+        assert!(!media.contains("#EXT-X-MAP"));
+        assert_eq!(media.matches("\nsegment.ts?").count(), 3);
+    }
+}
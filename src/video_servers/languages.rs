@@ -0,0 +1,158 @@
+/// Two letter ISO 639-1 codes.
+const ISO_639_1: [&str; 184] = [
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az", "ba", "be", "bg", "bh",
+    "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch", "co", "cr", "cs", "cu", "cv", "cy", "da",
+    "de", "dv", "dz", "ee", "el", "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr",
+    "fy", "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht", "hu", "hy", "hz",
+    "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it", "iu", "ja", "jv", "ka", "kg", "ki", "kj",
+    "kk", "kl", "km", "kn", "ko", "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln",
+    "lo", "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my", "na", "nb",
+    "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny", "oc", "oj", "om", "or", "os", "pa", "pi",
+    "pl", "ps", "pt", "qu", "rm", "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk",
+    "sl", "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te", "tg", "th", "ti",
+    "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty", "ug", "uk", "ur", "uz", "ve", "vi", "vo",
+    "wa", "wo", "xh", "yi", "yo", "za", "zh", "zu",
+];
+
+/// Three letter ISO 639-2 codes, both the terminology (`deu`) and the
+/// bibliographic (`ger`) ones.
+const ISO_639_2: [&str; 506] = [
+    "aar", "abk", "ace", "ach", "ada", "ady", "afa", "afh", "afr", "ain", "aka", "akk", "alb",
+    "ale", "alg", "alt", "amh", "ang", "anp", "apa", "ara", "arc", "arg", "arm", "arn", "arp",
+    "art", "arw", "asm", "ast", "ath", "aus", "ava", "ave", "awa", "aym", "aze", "bad", "bai",
+    "bak", "bal", "bam", "ban", "baq", "bas", "bat", "bej", "bel", "bem", "ben", "ber", "bho",
+    "bih", "bik", "bin", "bis", "bla", "bnt", "bod", "bos", "bra", "bre", "btk", "bua", "bug",
+    "bul", "bur", "byn", "cad", "cai", "car", "cat", "cau", "ceb", "cel", "ces", "cha", "chb",
+    "che", "chg", "chi", "chk", "chm", "chn", "cho", "chp", "chr", "chu", "chv", "chy", "cmc",
+    "cnr", "cop", "cor", "cos", "cpe", "cpf", "cpp", "cre", "crh", "crp", "csb", "cus", "cym",
+    "cze", "dak", "dan", "dar", "day", "del", "den", "deu", "dgr", "din", "div", "doi", "dra",
+    "dsb", "dua", "dum", "dut", "dyu", "dzo", "efi", "egy", "eka", "ell", "elx", "eng", "enm",
+    "epo", "est", "eus", "ewe", "ewo", "fan", "fao", "fas", "fat", "fij", "fil", "fin", "fiu",
+    "fon", "fra", "fre", "frm", "fro", "frr", "frs", "fry", "ful", "fur", "gaa", "gay", "gba",
+    "gem", "geo", "ger", "gez", "gil", "gla", "gle", "glg", "glv", "gmh", "goh", "gon", "gor",
+    "got", "grb", "grc", "gre", "grn", "gsw", "guj", "gwi", "hai", "hat", "hau", "haw", "heb",
+    "her", "hil", "him", "hin", "hit", "hmn", "hmo", "hrv", "hsb", "hun", "hup", "hye", "iba",
+    "ibo", "ice", "ido", "iii", "ijo", "iku", "ile", "ilo", "ina", "inc", "ind", "ine", "inh",
+    "ipk", "ira", "iro", "isl", "ita", "jav", "jbo", "jpn", "jpr", "jrb", "kaa", "kab", "kac",
+    "kal", "kam", "kan", "kar", "kas", "kat", "kau", "kaw", "kaz", "kbd", "kha", "khi", "khm",
+    "kho", "kik", "kin", "kir", "kmb", "kok", "kom", "kon", "kor", "kos", "kpe", "krc", "krl",
+    "kro", "kru", "kua", "kum", "kur", "kut", "lad", "lah", "lam", "lao", "lat", "lav", "lez",
+    "lim", "lin", "lit", "lol", "loz", "ltz", "lua", "lub", "lug", "lui", "lun", "luo", "lus",
+    "mac", "mad", "mag", "mah", "mai", "mak", "mal", "man", "mao", "map", "mar", "mas", "may",
+    "mdf", "mdr", "men", "mga", "mic", "min", "mis", "mkd", "mkh", "mlg", "mlt", "mnc", "mni",
+    "mno", "moh", "mon", "mos", "mri", "msa", "mul", "mun", "mus", "mwl", "mwr", "mya", "myn",
+    "myv", "nah", "nai", "nap", "nau", "nav", "nbl", "nde", "ndo", "nds", "nep", "new", "nia",
+    "nic", "niu", "nld", "nno", "nob", "nog", "non", "nor", "nqo", "nso", "nub", "nwc", "nya",
+    "nym", "nyn", "nyo", "nzi", "oci", "oji", "ori", "orm", "osa", "oss", "ota", "oto", "paa",
+    "pag", "pal", "pam", "pan", "pap", "pau", "peo", "per", "phi", "phn", "pli", "pol", "pon",
+    "por", "pra", "pro", "pus", "que", "raj", "rap", "rar", "roa", "roh", "rom", "ron", "rum",
+    "run", "rup", "rus", "sad", "sag", "sah", "sai", "sal", "sam", "san", "sas", "sat", "scn",
+    "sco", "sel", "sem", "sga", "sgn", "shn", "sid", "sin", "sio", "sit", "sla", "slk", "slo",
+    "slv", "sma", "sme", "smi", "smj", "smn", "smo", "sms", "sna", "snd", "snk", "sog", "som",
+    "son", "sot", "spa", "sqi", "srd", "srn", "srp", "srr", "ssa", "ssw", "suk", "sun", "sus",
+    "sux", "swa", "swe", "syc", "syr", "tah", "tai", "tam", "tat", "tel", "tem", "ter", "tet",
+    "tgk", "tgl", "tha", "tib", "tig", "tir", "tiv", "tkl", "tlh", "tli", "tmh", "tog", "ton",
+    "tpi", "tsi", "tsn", "tso", "tuk", "tum", "tup", "tur", "tut", "tvl", "twi", "tyv", "udm",
+    "uga", "uig", "ukr", "umb", "und", "urd", "uzb", "vai", "ven", "vie", "vol", "vot", "wak",
+    "wal", "war", "was", "wel", "wen", "wln", "wol", "xal", "xho", "yao", "yap", "yid", "yor",
+    "ypk", "zap", "zbl", "zen", "zgh", "zha", "zho", "znd", "zul", "zun", "zxx", "zza",
+];
+
+/// The ISO 639-2/B code of each ISO 639-1 code, in the same order.
+const ISO_639_1_BIBLIOGRAPHIC: [&str; 184] = [
+    "aar", "abk", "ave", "afr", "aka", "amh", "arg", "ara", "asm", "ava", "aym", "aze", "bak",
+    "bel", "bul", "bih", "bis", "bam", "ben", "tib", "bre", "bos", "cat", "che", "cha", "cos",
+    "cre", "cze", "chu", "chv", "wel", "dan", "ger", "div", "dzo", "ewe", "gre", "eng", "epo",
+    "spa", "est", "baq", "per", "ful", "fin", "fij", "fao", "fre", "fry", "gle", "gla", "glg",
+    "grn", "guj", "glv", "hau", "heb", "hin", "hmo", "hrv", "hat", "hun", "arm", "her", "ina",
+    "ind", "ile", "ibo", "iii", "ipk", "ido", "ice", "ita", "iku", "jpn", "jav", "geo", "kon",
+    "kik", "kua", "kaz", "kal", "khm", "kan", "kor", "kau", "kas", "kur", "kom", "cor", "kir",
+    "lat", "ltz", "lug", "lim", "lin", "lao", "lit", "lub", "lav", "mlg", "mah", "mao", "mac",
+    "mal", "mon", "mar", "may", "mlt", "bur", "nau", "nob", "nde", "nep", "ndo", "dut", "nno",
+    "nor", "nbl", "nav", "nya", "oci", "oji", "orm", "ori", "oss", "pan", "pli", "pol", "pus",
+    "por", "que", "roh", "run", "rum", "rus", "kin", "san", "srd", "snd", "sme", "sag", "sin",
+    "slo", "slv", "smo", "sna", "som", "alb", "srp", "ssw", "sot", "sun", "swe", "swa", "tam",
+    "tel", "tgk", "tha", "tir", "tuk", "tgl", "tsn", "ton", "tur", "tso", "tat", "twi", "tah",
+    "uig", "ukr", "urd", "uzb", "ven", "vie", "vol", "wln", "wol", "xho", "yid", "yor", "zha",
+    "chi", "zul",
+];
+
+/// ISO 639-2 terminology codes and the bibliographic codes that differ from
+/// them, sorted by the former.
+const TERMINOLOGY_TO_BIBLIOGRAPHIC: [(&str, &str); 20] = [
+    ("bod", "tib"),
+    ("ces", "cze"),
+    ("cym", "wel"),
+    ("deu", "ger"),
+    ("ell", "gre"),
+    ("eus", "baq"),
+    ("fas", "per"),
+    ("fra", "fre"),
+    ("hye", "arm"),
+    ("isl", "ice"),
+    ("kat", "geo"),
+    ("mkd", "mac"),
+    ("mri", "mao"),
+    ("msa", "may"),
+    ("mya", "bur"),
+    ("nld", "dut"),
+    ("ron", "rum"),
+    ("slk", "slo"),
+    ("sqi", "alb"),
+    ("zho", "chi"),
+];
+
+/// The ISO 639-2/B code, the form Matroska files tag tracks with, of an
+/// ISO 639-1 or ISO 639-2 code in lowercase, or `None` if `code` is neither.
+pub fn bibliographic(code: &str) -> Option<&'static str> {
+    match code.len() {
+        2 => Some(ISO_639_1_BIBLIOGRAPHIC[ISO_639_1.binary_search(&code).ok()?]),
+        3 => {
+            let code = ISO_639_2[ISO_639_2.binary_search(&code).ok()?];
+            Some(
+                match TERMINOLOGY_TO_BIBLIOGRAPHIC.binary_search_by_key(&code, |(t, _)| t) {
+                    Ok(index) => TERMINOLOGY_TO_BIBLIOGRAPHIC[index].1,
+                    Err(_) => code,
+                },
+            )
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_lists_are_sorted_for_binary_search() {
+        assert!(ISO_639_1.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ISO_639_2.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn terminology_codes_are_sorted_and_known() {
+        assert!(TERMINOLOGY_TO_BIBLIOGRAPHIC
+            .windows(2)
+            .all(|pair| pair[0].0 < pair[1].0));
+        for (terminology, bibliographic) in TERMINOLOGY_TO_BIBLIOGRAPHIC {
+            assert!(ISO_639_2.binary_search(&terminology).is_ok());
+            assert!(ISO_639_2.binary_search(&bibliographic).is_ok());
+        }
+    }
+
+    #[test]
+    fn bibliographic_accepts_both_lengths_only() {
+        assert_eq!(bibliographic("hi"), Some("hin"));
+        assert_eq!(bibliographic("en"), Some("eng"));
+        assert_eq!(bibliographic("zu"), Some("zul"));
+        assert_eq!(bibliographic("de"), Some("ger"));
+        assert_eq!(bibliographic("ger"), Some("ger"));
+        assert_eq!(bibliographic("deu"), Some("ger"));
+        assert_eq!(bibliographic("zho"), Some("chi"));
+        assert_eq!(bibliographic("aar"), Some("aar"));
+        for code in ["xx", "xyz", "eng1", ""] {
+            assert_eq!(bibliographic(code), None);
+        }
+    }
+}
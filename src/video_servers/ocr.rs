@@ -0,0 +1,634 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fnv::FnvHasher;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use super::ffmpeg::FfmpegInfo;
+use super::transcode_manager::{TranscodeJob, TranscodeManager};
+
+/// PGS timestamps are in 90 kHz ticks.
+const PGS_CLOCK: f64 = 90_000.0;
+/// Shown for this long if the stream never clears the last caption.
+const LAST_CAPTION_SECS: f64 = 5.0;
+/// Images passed to one tesseract run.
+const BATCH_SIZE: usize = 100;
+/// White border around each caption image, which helps tesseract.
+const PADDING: usize = 10;
+/// tesseract runs at once over all tracks. Each one keeps a core busy, and
+/// OCR jobs otherwise only share the background transcode slots.
+const MAX_TESSERACT_RUNS: usize = 2;
+
+static TESSERACT_RUNS: Semaphore = Semaphore::const_new(MAX_TESSERACT_RUNS);
+
+/// Bitmap subtitle codecs OCR can read.
+pub fn supports(codec: &str) -> bool {
+    codec == "hdmv_pgs_subtitle"
+}
+
+/// A caption rendered as black text on white, one byte per pixel.
+struct Bitmap {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Bitmap {
+    /// Binary PGM, which tesseract reads without any extra libraries.
+    fn to_pgm(&self) -> Vec<u8> {
+        let mut data = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend_from_slice(&self.pixels);
+        data
+    }
+}
+
+struct Caption {
+    start: f64,
+    end: Option<f64>,
+    image: Bitmap,
+}
+
+/// A decoded PGS object: palette indexes, row by row.
+struct Object {
+    width: usize,
+    height: usize,
+    /// Run-length encoded data, possibly still missing fragments.
+    rle: Vec<u8>,
+}
+
+impl Object {
+    fn decode(&self) -> Vec<u8> {
+        let mut pixels = vec![0; self.width * self.height];
+        let mut data = self.rle.iter().copied();
+        let (mut x, mut y) = (0, 0);
+        let mut put = |x: &mut usize, y: usize, count: usize, color: u8| {
+            for _ in 0..count {
+                if *x < self.width && y < self.height {
+                    pixels[y * self.width + *x] = color;
+                }
+                *x += 1;
+            }
+        };
+        while let Some(byte) = data.next() {
+            if byte != 0 {
+                put(&mut x, y, 1, byte);
+                continue;
+            }
+            let Some(flags) = data.next() else { break };
+            let short = (flags & 0x3f) as usize;
+            match flags >> 6 {
+                _ if flags == 0 => {
+                    x = 0;
+                    y += 1;
+                }
+                0 => put(&mut x, y, short, 0),
+                1 => {
+                    let count = short << 8 | data.next().unwrap_or(0) as usize;
+                    put(&mut x, y, count, 0);
+                }
+                2 => {
+                    let color = data.next().unwrap_or(0);
+                    put(&mut x, y, short, color);
+                }
+                _ => {
+                    let count = short << 8 | data.next().unwrap_or(0) as usize;
+                    let color = data.next().unwrap_or(0);
+                    put(&mut x, y, count, color);
+                }
+            }
+        }
+        pixels
+    }
+}
+
+/// Where a composition shows an object.
+struct Placement {
+    object: u16,
+    x: usize,
+    y: usize,
+}
+
+fn read_u16(data: &[u8], at: usize) -> usize {
+    data.get(at..at + 2)
+        .map_or(0, |bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+/// Draws the objects of a composition onto one image cropped to them.
+/// Subtitles are light text with a dark outline, so bright opaque pixels
+/// become the ink.
+fn render(
+    placements: &[Placement],
+    objects: &HashMap<u16, Object>,
+    palette: &[(u8, u8); 256],
+) -> Option<Bitmap> {
+    let placed: Vec<_> = placements
+        .iter()
+        .filter_map(|placement| {
+            let object = objects.get(&placement.object)?;
+            Some((object, placement.x, placement.y))
+        })
+        .filter(|(object, _, _)| object.width > 0 && object.height > 0)
+        .collect();
+    let left = placed.iter().map(|(_, x, _)| *x).min()?;
+    let top = placed.iter().map(|(_, _, y)| *y).min()?;
+    let right = placed.iter().map(|(o, x, _)| x + o.width).max()?;
+    let bottom = placed.iter().map(|(o, _, y)| y + o.height).max()?;
+    let width = right - left + 2 * PADDING;
+    let height = bottom - top + 2 * PADDING;
+    let mut pixels = vec![255; width * height];
+    for (object, x, y) in placed {
+        for (index, color) in object.decode().into_iter().enumerate() {
+            let (luma, alpha) = palette[color as usize];
+            if alpha >= 128 && luma >= 128 {
+                let row = y - top + PADDING + index / object.width;
+                let column = x - left + PADDING + index % object.width;
+                pixels[row * width + column] = 0;
+            }
+        }
+    }
+    Some(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Reads the captions of a PGS stream as written by ffmpeg's `sup` muxer.
+fn parse_pgs(data: &[u8]) -> Vec<Caption> {
+    let mut captions: Vec<Caption> = Vec::new();
+    let mut palettes: HashMap<u8, [(u8, u8); 256]> = HashMap::new();
+    let mut objects: HashMap<u16, Object> = HashMap::new();
+    // The composition being built: its start, palette and object positions.
+    let mut composition: Option<(f64, u8, Vec<Placement>)> = None;
+    let mut at = 0;
+    while at + 13 <= data.len() && &data[at..at + 2] == b"PG" {
+        let pts = u32::from_be_bytes(data[at + 2..at + 6].try_into().unwrap()) as f64 / PGS_CLOCK;
+        let kind = data[at + 10];
+        let size = read_u16(data, at + 11);
+        let Some(segment) = data.get(at + 13..at + 13 + size) else {
+            break;
+        };
+        at += 13 + size;
+        match kind {
+            // Palette: id, version, then id, Y, Cr, Cb, alpha per entry.
+            0x14 if segment.len() >= 2 => {
+                let palette = palettes.entry(segment[0]).or_insert([(0, 0); 256]);
+                for entry in segment[2..].chunks_exact(5) {
+                    palette[entry[0] as usize] = (entry[1], entry[4]);
+                }
+            }
+            // Object: id, version, sequence flags, then the size and data in
+            // the first fragment and only data in the others.
+            0x15 if segment.len() >= 4 => {
+                let id = read_u16(segment, 0) as u16;
+                if segment[3] & 0x80 != 0 && segment.len() >= 11 {
+                    objects.insert(
+                        id,
+                        Object {
+                            width: read_u16(segment, 7),
+                            height: read_u16(segment, 9),
+                            rle: segment[11..].to_vec(),
+                        },
+                    );
+                } else if let Some(object) = objects.get_mut(&id) {
+                    object.rle.extend_from_slice(&segment[4..]);
+                }
+            }
+            // Presentation composition: video size, frame rate, number,
+            // state, palette update flag, palette id, then the objects.
+            0x16 if segment.len() >= 11 => {
+                // An epoch start replaces every object.
+                if segment[7] & 0x80 != 0 {
+                    objects.clear();
+                }
+                let mut placements = Vec::new();
+                let mut offset = 11;
+                for _ in 0..segment[10] {
+                    if offset + 8 > segment.len() {
+                        break;
+                    }
+                    placements.push(Placement {
+                        object: read_u16(segment, offset) as u16,
+                        x: read_u16(segment, offset + 4),
+                        y: read_u16(segment, offset + 6),
+                    });
+                    // Cropped objects carry a crop window; 0x40 only marks
+                    // forced captions.
+                    offset += if segment[offset + 3] & 0x80 != 0 {
+                        16
+                    } else {
+                        8
+                    };
+                }
+                composition = Some((pts, segment[9], placements));
+            }
+            // End of display set: the composition is complete.
+            0x80 => {
+                let Some((start, palette_id, placements)) = composition.take() else {
+                    continue;
+                };
+                if let Some(last) = captions.last_mut() {
+                    if last.end.is_none() {
+                        last.end = Some(start);
+                    }
+                }
+                let palette = palettes.get(&palette_id).copied().unwrap_or([(0, 0); 256]);
+                if let Some(image) = render(&placements, &objects, &palette) {
+                    captions.push(Caption {
+                        start,
+                        end: None,
+                        image,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    captions
+}
+
+/// tesseract names languages by ISO 639-2/T code, where they differ from
+/// the bibliographic ones found in files.
+fn tesseract_language(language: Option<&str>) -> String {
+    let language = match language {
+        Some(language) if language.len() == 3 => language,
+        _ => return "eng".to_string(),
+    };
+    match language {
+        "alb" => "sqi",
+        "arm" => "hye",
+        "baq" => "eus",
+        "bur" => "mya",
+        "chi" => "chi_sim",
+        "cze" => "ces",
+        "dut" => "nld",
+        "fre" => "fra",
+        "geo" => "kat",
+        "ger" => "deu",
+        "gre" => "ell",
+        "ice" => "isl",
+        "mac" => "mkd",
+        "may" => "msa",
+        "per" => "fas",
+        "rum" => "ron",
+        "slo" => "slk",
+        "wel" => "cym",
+        language => language,
+    }
+    .to_string()
+}
+
+/// What a process run by an OCR job wrote, and whether it succeeded.
+struct Output {
+    success: bool,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Runs `command` as part of `job`, so it counts against the transcode
+/// limits and is killed with the job, and collects its output.
+async fn output(job: &TranscodeJob, mut command: Command) -> std::io::Result<Output> {
+    command.stderr(Stdio::piped());
+    let mut process = job.spawn(command)?;
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    if let (Some(mut out), Some(mut err)) = (process.take_stdout(), process.take_stderr()) {
+        tokio::try_join!(out.read_to_end(&mut stdout), err.read_to_end(&mut stderr))?;
+    }
+    Ok(Output {
+        success: process.wait().await,
+        stdout,
+        stderr,
+    })
+}
+
+/// Languages the local tesseract has data for.
+async fn installed_languages(
+    job: &TranscodeJob,
+    tesseract: &str,
+) -> Result<HashSet<String>, String> {
+    let mut command = Command::new(tesseract);
+    command.arg("--list-langs");
+    let output = output(job, command)
+        .await
+        .map_err(|e| format!("Failed to run {tesseract}: {e}"))?;
+    // Older versions print the list to stderr.
+    let text = [output.stdout, output.stderr].concat();
+    Ok(String::from_utf8_lossy(&text)
+        .lines()
+        .skip(1)
+        .map(|line| line.trim().to_string())
+        .collect())
+}
+
+/// Reads the text of each image, in order. One tesseract run handles a
+/// whole batch from a list file, and separates pages with form feeds.
+async fn recognize(
+    job: &TranscodeJob,
+    tesseract: &str,
+    language: &str,
+    dir: &Path,
+    images: &[&Bitmap],
+) -> Result<Vec<String>, String> {
+    let mut list = String::new();
+    for (index, image) in images.iter().enumerate() {
+        let file = dir.join(format!("{index:04}.pgm"));
+        fs::write(&file, image.to_pgm())
+            .map_err(|e| format!("Failed to write subtitle image: {e}"))?;
+        writeln!(list, "{}", file.display()).unwrap();
+    }
+    let list_file = dir.join("images.txt");
+    fs::write(&list_file, list).map_err(|e| format!("Failed to write image list: {e}"))?;
+    let mut command = Command::new(tesseract);
+    command
+        .arg(&list_file)
+        .arg("stdout")
+        .args(["-l", language])
+        .args(["--psm", "6"]);
+    // The semaphore is never closed.
+    let _run = TESSERACT_RUNS.acquire().await.unwrap();
+    let output = output(job, command)
+        .await
+        .map_err(|e| format!("Failed to run {tesseract}: {e}"))?;
+    if !output.success {
+        return Err(format!(
+            "{tesseract} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut pages: Vec<String> = text
+        .split('\x0c')
+        .map(|page| page.trim().to_string())
+        .collect();
+    // Older versions also end the last page with a form feed.
+    if pages.len() == images.len() + 1 && pages.last().is_some_and(String::is_empty) {
+        pages.pop();
+    }
+    if pages.len() != images.len() {
+        return Err(format!(
+            "tesseract returned {} pages for {} images",
+            pages.len(),
+            images.len()
+        ));
+    }
+    Ok(pages)
+}
+
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn push_cue(vtt: &mut String, start: f64, end: f64, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    let text = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    write!(
+        vtt,
+        "\n{} --> {}\n{text}\n",
+        vtt_timestamp(start),
+        vtt_timestamp(end)
+    )
+    .unwrap();
+}
+
+/// Runs OCR over a PGS track and returns it as WebVTT.
+async fn ocr_track(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    tesseract: &str,
+    path: &str,
+    id: u64,
+    language: Option<&str>,
+) -> Result<String, String> {
+    let mut command = Command::new(&ffmpeg.ffmpeg_path);
+    command
+        .args(["-v", "error"])
+        .args(["-i", path])
+        .args(["-map", &format!("0:s:{id}")])
+        .args(["-c:s", "copy", "-f", "sup", "pipe:1"]);
+    let output = output(job, command)
+        .await
+        .map_err(|e| format!("Failed to execute ffmpeg: {e}"))?;
+    if !output.success {
+        return Err(format!(
+            "Failed to extract subtitle track {id}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let captions = parse_pgs(&output.stdout);
+    let mut language = tesseract_language(language);
+    if !installed_languages(job, tesseract)
+        .await?
+        .contains(&language)
+    {
+        println!("No tesseract data for {language}, reading subtitles as English");
+        language = "eng".to_string();
+    }
+    println!(
+        "Reading {} subtitle images of {path} track {id}",
+        captions.len()
+    );
+
+    let dir = tempfile::Builder::new()
+        .prefix("nexus-ocr-")
+        .tempdir()
+        .map_err(|e| format!("Failed to create OCR directory: {e}"))?;
+    let mut vtt = "WEBVTT\n".to_string();
+    // The same text shown again straight away, e.g. while fading, is one cue.
+    let mut previous: Option<(f64, f64, String)> = None;
+    for batch in captions.chunks(BATCH_SIZE) {
+        let images: Vec<&Bitmap> = batch.iter().map(|caption| &caption.image).collect();
+        let texts = recognize(job, tesseract, &language, dir.path(), &images).await?;
+        for (caption, text) in batch.iter().zip(texts) {
+            let end = caption.end.unwrap_or(caption.start + LAST_CAPTION_SECS);
+            match &mut previous {
+                Some((_, previous_end, previous_text))
+                    if *previous_text == text && (caption.start - *previous_end).abs() < 0.05 =>
+                {
+                    *previous_end = end;
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some((start, end, text)) = previous.replace((caption.start, end, text)) {
+                push_cue(&mut vtt, start, end, &text);
+            }
+        }
+    }
+    if let Some((start, end, text)) = previous {
+        push_cue(&mut vtt, start, end, &text);
+    }
+    Ok(vtt)
+}
+
+/// Where the OCR result for a track of this version of the file goes.
+fn cache_file(path: &str, id: u64) -> Option<PathBuf> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let mut hasher = FnvHasher::default();
+    path.hash(&mut hasher);
+    mtime.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    id.hash(&mut hasher);
+    Some(
+        super::cache_dir()
+            .join("subtitle-ocr")
+            .join(format!("{:016x}.vtt", hasher.finish())),
+    )
+}
+
+/// A track that failed is not read again for this long.
+const FAILURE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// When reading a track failed, and why.
+type Failure = (Instant, String);
+
+/// Tracks being read, or how reading them failed, by cache file. Failures
+/// are kept for a while so clients polling for the result see them.
+static JOBS: LazyLock<Mutex<HashMap<PathBuf, Option<Failure>>>> = LazyLock::new(Default::default);
+
+pub enum OcrStatus {
+    /// WebVTT file with the text of the track.
+    Done(PathBuf),
+    /// Being read in the background; ask again later.
+    Running,
+}
+
+/// The OCR result for a bitmap track, starting it in the background if it
+/// has not been made yet. It runs as a background transcode job once a slot
+/// is free. Results are kept in the cache directory until the video file
+/// changes.
+pub fn ocr_subtitle(
+    ffmpeg: Arc<FfmpegInfo>,
+    manager: Arc<TranscodeManager>,
+    tesseract: String,
+    path: &str,
+    id: u64,
+    language: Option<String>,
+) -> Result<OcrStatus, String> {
+    let file = cache_file(path, id).ok_or_else(|| format!("File not found: {path}"))?;
+    if file.exists() {
+        return Ok(OcrStatus::Done(file));
+    }
+    match JOBS.lock().unwrap().entry(file.clone()) {
+        Entry::Occupied(mut job) => match job.get() {
+            Some((failed, e)) if failed.elapsed() < FAILURE_TIMEOUT => return Err(e.clone()),
+            Some(_) => {
+                job.insert(None);
+            }
+            None => return Ok(OcrStatus::Running),
+        },
+        Entry::Vacant(job) => {
+            job.insert(None);
+        }
+    }
+    let path = path.to_string();
+    tokio::spawn(async move {
+        let job = manager.start_background_job(&path).await;
+        let result = ocr_track(&ffmpeg, &job, &tesseract, &path, id, language.as_deref()).await;
+        drop(job);
+        // Written aside and renamed, so a partly written file is never
+        // taken for the result.
+        let partial = file.with_extension("vtt.partial");
+        let written = result.and_then(|vtt| {
+            file.parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&partial, vtt))
+                .and_then(|_| fs::rename(&partial, &file))
+                .map_err(|e| format!("Failed to save OCR result: {e}"))
+        });
+        let mut jobs = JOBS.lock().unwrap();
+        match written {
+            Ok(()) => {
+                println!("Read subtitle track {id} of {path}");
+                jobs.remove(&file);
+            }
+            Err(e) => {
+                eprintln!("Subtitle OCR of {path} track {id} failed: {e}");
+                jobs.insert(file, Some((Instant::now(), e)));
+            }
+        }
+    });
+    Ok(OcrStatus::Running)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = b"PG".to_vec();
+        data.extend_from_slice(&90_000u32.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.push(kind);
+        data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// An object of one opaque white color, as RLE rows.
+    fn object(id: u16, width: u16, height: u16) -> Vec<u8> {
+        let mut payload = id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[0, 0xc0, 0, 0, 0]);
+        payload.extend_from_slice(&width.to_be_bytes());
+        payload.extend_from_slice(&height.to_be_bytes());
+        for _ in 0..height {
+            payload.extend(std::iter::repeat_n(1, width as usize));
+            payload.extend_from_slice(&[0, 0]);
+        }
+        segment(0x15, &payload)
+    }
+
+    #[test]
+    fn parse_pgs_reads_forced_and_cropped_objects() {
+        // Video size, frame rate, number, epoch start, no palette update,
+        // palette 0, two objects.
+        let mut composition = vec![0x07, 0x80, 0x04, 0x38, 0x10, 0, 1, 0x80, 0, 0, 2];
+        // Object 1 is forced and not cropped, at (100, 200).
+        composition.extend_from_slice(&[0, 1, 0, 0x40, 0, 100, 0, 200]);
+        // Object 2 is cropped, at (110, 210), with a crop window.
+        composition.extend_from_slice(&[0, 2, 0, 0x80, 0, 110, 0, 210]);
+        composition.extend_from_slice(&[0, 0, 0, 0, 0, 2, 0, 2]);
+
+        let mut data = segment(0x16, &composition);
+        data.extend(segment(0x14, &[0, 0, 1, 255, 128, 128, 255]));
+        data.extend(object(1, 4, 2));
+        data.extend(object(2, 2, 2));
+        data.extend(segment(0x80, &[]));
+
+        let captions = parse_pgs(&data);
+        assert_eq!(captions.len(), 1);
+        let image = &captions[0].image;
+        assert_eq!(image.width, 12 + 2 * PADDING);
+        assert_eq!(image.height, 12 + 2 * PADDING);
+        let ink = |x: usize, y: usize| image.pixels[(y + PADDING) * image.width + x + PADDING] == 0;
+        assert!(ink(0, 0) && ink(3, 1));
+        assert!(ink(10, 10) && ink(11, 11));
+        assert_eq!(image.pixels.iter().filter(|pixel| **pixel == 0).count(), 12);
+    }
+}
@@ -0,0 +1,283 @@
+use serde::Serialize;
+
+use super::device_profile::DeviceProfile;
+use super::encoders::{AudioCodec, EncoderBackend, Normalization, VideoCodec};
+
+/// How a file reaches the client.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackMode {
+    /// The original file is served as is with byte ranges.
+    DirectPlay,
+    /// Streams are copied into fragmented MP4 without re-encoding.
+    Remux,
+    /// Video is re-encoded by the encoder backend.
+    Transcode,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PlaybackDecision {
+    pub mode: PlaybackMode,
+    pub reason: String,
+    /// Whether the audio tracks can be copied instead of re-encoded.
+    pub copy_audio: bool,
+    /// Codecs the streams are encoded to when they are not copied.
+    pub video_target: VideoCodec,
+    pub audio_target: AudioCodec,
+}
+
+/// The parts of the ffprobe output the playback decision depends on.
+#[derive(Debug, Default)]
+pub struct SourceInfo {
+    pub format_name: String,
+    pub video_codec: Option<String>,
+    pub video_profile: Option<String>,
+    pub video_level: Option<i64>,
+    pub pixel_format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub hdr: bool,
+    /// Dolby Vision profile and level of the video, if known.
+    pub dolby_vision: Option<(u32, u32)>,
+    pub audio: Vec<AudioSource>,
+    pub subtitle_codecs: Vec<String>,
+}
+
+/// An audio track of the source.
+#[derive(Debug)]
+pub struct AudioSource {
+    pub codec: String,
+    pub profile: Option<String>,
+    pub channels: Option<u32>,
+}
+
+/// RFC 6381 codec string of an H.264 stream, e.g. `avc1.640028`.
+pub fn avc_codec_string(profile: &str, level: i64) -> Option<String> {
+    let (profile_idc, constraints) = match profile {
+        "Constrained Baseline" => (0x42, 0xe0),
+        "Baseline" => (0x42, 0x00),
+        "Main" => (0x4d, 0x00),
+        "High" => (0x64, 0x00),
+        _ => return None,
+    };
+    Some(format!(
+        "avc1.{profile_idc:02x}{constraints:02x}{level:02x}"
+    ))
+}
+
+/// RFC 6381 codec string of an HEVC stream, e.g. `hvc1.1.6.L93.B0`.
+/// ffprobe reports HEVC levels multiplied by 30, as the string wants them.
+pub fn hevc_codec_string(profile: &str, level: i64) -> Option<String> {
+    let prefix = match profile {
+        "Main" => "hvc1.1.6",
+        "Main 10" => "hvc1.2.4",
+        _ => return None,
+    };
+    Some(format!("{prefix}.L{level}.B0"))
+}
+
+/// Codec string of Dolby Vision over HEVC, e.g. `dvh1.08.06`, as Dolby
+/// specifies it for MP4 with out-of-band parameter sets.
+pub fn dolby_vision_codec_string(profile: u32, level: u32) -> String {
+    format!("dvh1.{profile:02}.{level:02}")
+}
+
+/// RFC 6381 codec string of an audio stream that can be copied into MP4.
+pub fn audio_codec_string(codec: &str, profile: Option<&str>) -> Option<&'static str> {
+    match (codec, profile) {
+        // Browsers only decode plain AAC-LC reliably from MP4.
+        ("aac", Some("LC")) => Some("mp4a.40.2"),
+        ("opus", _) => Some("opus"),
+        ("ac3", _) => Some("ac-3"),
+        ("eac3", _) => Some("ec-3"),
+        ("flac", _) => Some("flac"),
+        _ => None,
+    }
+}
+
+impl SourceInfo {
+    /// Container names a device profile may list for the source. ffprobe
+    /// calls every Matroska file `matroska,webm`, so it only counts as WebM
+    /// when all of its streams are ones WebM allows.
+    pub fn containers(&self) -> Vec<&'static str> {
+        let formats: Vec<&str> = self.format_name.split(',').collect();
+        if formats.contains(&"mp4") {
+            vec!["mp4"]
+        } else if formats.contains(&"matroska") && self.fits_webm() {
+            vec!["mkv", "webm"]
+        } else if formats.contains(&"matroska") {
+            vec!["mkv"]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn fits_webm(&self) -> bool {
+        self.video_codec
+            .as_deref()
+            .is_none_or(|codec| matches!(codec, "vp8" | "vp9" | "av1"))
+            && self
+                .audio
+                .iter()
+                .all(|audio| matches!(audio.codec.as_str(), "opus" | "vorbis"))
+            && self.subtitle_codecs.iter().all(|codec| codec == "webvtt")
+    }
+
+    /// Codec string of the source video if the client can decode it as is.
+    pub fn compatible_video_codec(&self, profile: &DeviceProfile) -> Result<String, String> {
+        let codec = self.video_codec.as_deref().unwrap_or("none");
+        if !profile.supports_video(codec) {
+            return Err(format!(
+                "video codec {codec} is not supported by the client"
+            ));
+        }
+        if self.hdr && !profile.hdr {
+            return Err("HDR video needs tone mapping for an SDR display".to_string());
+        }
+        let (max_width, max_height) = profile.max_size();
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            if width > max_width || height > max_height {
+                return Err(format!(
+                    "{width}x{height} video is larger than the client supports"
+                ));
+            }
+        }
+        let pixel_format = self.pixel_format.as_deref().unwrap_or("unknown");
+        let video_profile = self.video_profile.as_deref().unwrap_or("unknown");
+        let level = self.video_level.unwrap_or(0);
+        let codec_string = match codec {
+            "h264" if matches!(pixel_format, "yuv420p" | "yuvj420p") => {
+                avc_codec_string(video_profile, level)
+            }
+            // Dolby Vision is advertised as such, so players can tell
+            // whether they can show it.
+            "hevc" if matches!(pixel_format, "yuv420p" | "yuv420p10le") => {
+                match self.dolby_vision {
+                    Some((dv_profile, dv_level)) => {
+                        Some(dolby_vision_codec_string(dv_profile, dv_level))
+                    }
+                    None => hevc_codec_string(video_profile, level),
+                }
+            }
+            "h264" | "hevc" => {
+                return Err(format!(
+                    "pixel format {pixel_format} is not browser compatible"
+                ))
+            }
+            _ => return Err(format!("video codec {codec} cannot be copied into MP4")),
+        };
+        codec_string
+            .ok_or_else(|| format!("{codec} profile {video_profile} is not browser compatible"))
+    }
+
+    /// Codec string of the source audio if every track can be sent as is:
+    /// the client decodes it, it is the codec the profile asks for, if any,
+    /// and it has no more channels than the client outputs. Night mode needs
+    /// re-encoding. All tracks share one codec string in the metadata.
+    pub fn compatible_audio_codec(&self, profile: &DeviceProfile) -> Option<&'static str> {
+        if profile.normalization == Normalization::Night {
+            return None;
+        }
+        let mut codecs = self.audio.iter().map(|audio| {
+            let wanted = profile
+                .audio_codec
+                .is_none_or(|codec| codec.name() == audio.codec);
+            let fits = match (profile.audio_channels, audio.channels) {
+                (Some(max), Some(channels)) => channels <= max,
+                _ => true,
+            };
+            audio_codec_string(&audio.codec, audio.profile.as_deref())
+                .filter(|_| profile.supports_audio(&audio.codec) && wanted && fits)
+        });
+        let first = codecs.next()??;
+        codecs.all(|codec| codec == Some(first)).then_some(first)
+    }
+}
+
+/// The codec video is encoded to: the backend's own codec if the client
+/// plays it, otherwise another one the client plays and ffmpeg can encode.
+fn video_target(
+    profile: &DeviceProfile,
+    encoder: EncoderBackend,
+    encoders: &[String],
+) -> VideoCodec {
+    let native = encoder.native_codec();
+    if profile.supports_video(native.name()) {
+        return native;
+    }
+    [VideoCodec::H264, VideoCodec::Hevc]
+        .into_iter()
+        .find(|codec| {
+            profile.supports_video(codec.name())
+                && encoders
+                    .iter()
+                    .any(|name| name == encoder.encoder_name_for(*codec))
+        })
+        .unwrap_or(native)
+}
+
+/// The codec audio is encoded to: the one the profile asks for, else Opus
+/// if the client plays it, falling back to AAC when ffmpeg lacks the encoder.
+fn audio_target(profile: &DeviceProfile, encoders: &[String]) -> AudioCodec {
+    let wanted = match profile.audio_codec {
+        Some(codec) => codec,
+        None if profile.supports_audio("opus") => AudioCodec::Opus,
+        None => AudioCodec::Aac,
+    };
+    if encoders.iter().any(|name| name == wanted.encoder_name()) {
+        wanted
+    } else {
+        AudioCodec::Aac
+    }
+}
+
+pub fn decide(
+    source: &SourceInfo,
+    profile: &DeviceProfile,
+    encoder: EncoderBackend,
+    encoders: &[String],
+) -> PlaybackDecision {
+    let video_target = video_target(profile, encoder, encoders);
+    let audio_target = audio_target(profile, encoders);
+    let copy_audio = source.compatible_audio_codec(profile).is_some();
+    let video_codec = source.video_codec.as_deref().unwrap_or("none");
+    if let Err(reason) = source.compatible_video_codec(profile) {
+        return PlaybackDecision {
+            mode: PlaybackMode::Transcode,
+            reason: format!("{reason}, encoding to {}", video_target.name()),
+            copy_audio,
+            video_target,
+            audio_target,
+        };
+    }
+
+    let remux_reason = if !profile.supports_container(&source.containers()) {
+        Some(format!(
+            "container {} is not supported by the client",
+            source.format_name
+        ))
+    } else if !source.audio.is_empty() && !copy_audio {
+        Some("audio needs re-encoding".to_string())
+    } else if source.audio.len() > 1 {
+        Some("multiple audio tracks need the chunked player".to_string())
+    } else {
+        None
+    };
+
+    match remux_reason {
+        Some(reason) => PlaybackDecision {
+            mode: PlaybackMode::Remux,
+            reason: format!("{reason}, but {video_codec} video can be copied"),
+            copy_audio,
+            video_target,
+            audio_target,
+        },
+        None => PlaybackDecision {
+            mode: PlaybackMode::DirectPlay,
+            reason: "the client plays this file natively".to_string(),
+            copy_audio,
+            video_target,
+            audio_target,
+        },
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Reads a value ffprobe may write either as a number or as a string, such
+/// as `"duration": "5400.120000"`.
+fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(value)) => value.parse().ok(),
+        Some(Value::Number(value)) => value.to_string().parse().ok(),
+        _ => None,
+    })
+}
+
+/// Reads a frame rate written as a fraction, e.g. `"24000/1001"`.
+fn frame_rate<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(value)) => match value.split_once('/') {
+            Some((num, den)) => match (num.parse::<f64>(), den.parse::<f64>()) {
+                (Ok(num), Ok(den)) if num > 0.0 && den > 0.0 => Some(num / den),
+                _ => None,
+            },
+            None => value.parse().ok(),
+        },
+        Some(Value::Number(value)) => value.as_f64(),
+        _ => None,
+    })
+}
+
+/// Reads a disposition flag, which ffprobe writes as 0 or 1.
+fn flag<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Bool(value)) => value,
+        Some(Value::Number(value)) => value.as_i64().unwrap_or(0) != 0,
+        _ => false,
+    })
+}
+
+/// `ffprobe -show_format -show_streams -show_chapters` output.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MediaInfo {
+    pub format: FormatInfo,
+    #[serde(default)]
+    pub streams: Vec<StreamInfo>,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FormatInfo {
+    #[serde(default)]
+    pub format_name: String,
+    #[serde(default)]
+    pub format_long_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub duration: Option<f64>,
+    /// Overall bitrate in bits per second.
+    #[serde(default, deserialize_with = "lenient")]
+    pub bit_rate: Option<u64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamType {
+    Video,
+    Audio,
+    Subtitle,
+    Attachment,
+    Data,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HdrFormat {
+    /// SMPTE ST 2084 (PQ) transfer, including HDR10+.
+    Hdr10,
+    Hlg,
+    /// Dolby Vision without a base layer other displays can show, i.e.
+    /// profile 5. It can only be copied.
+    DolbyVision,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Disposition {
+    #[serde(default, deserialize_with = "flag")]
+    pub default: bool,
+    #[serde(default, deserialize_with = "flag")]
+    pub forced: bool,
+    #[serde(default, deserialize_with = "flag")]
+    pub hearing_impaired: bool,
+    #[serde(default, deserialize_with = "flag")]
+    pub attached_pic: bool,
+}
+
+/// Reads tags, keeping only those with a string value.
+fn string_tags<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(HashMap::<String, Value>::deserialize(deserializer)?
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Value::String(value) => Some((name, value)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Stream or chapter tags. Their case depends on the container, e.g.
+/// `language` in MP4 and `LANGUAGE` in some Matroska files, so they are
+/// looked up ignoring case.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(transparent)]
+pub struct Tags(#[serde(deserialize_with = "string_tags")] HashMap<String, String>);
+
+impl Tags {
+    /// The value of a tag, preferring the lowercase name when a file has
+    /// it in more than one case.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .get(name)
+            .or_else(|| {
+                self.0
+                    .iter()
+                    .find(|(other, _)| other.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value)
+            })
+            .map(String::as_str)
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.get("language")
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.get("title")
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        self.get("filename")
+    }
+
+    pub fn mimetype(&self) -> Option<&str> {
+        self.get("mimetype")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SideData {
+    #[serde(default)]
+    pub side_data_type: String,
+    /// Dolby Vision profile, e.g. 5, 7 or 8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dv_profile: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dv_level: Option<u32>,
+    /// What the Dolby Vision base layer can be shown as without Dolby
+    /// Vision: 0 for nothing (profile 5), 1 or 6 for HDR10, 2 for SDR and 4
+    /// for HLG.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dv_bl_signal_compatibility_id: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamInfo {
+    pub index: u64,
+    #[serde(default = "unknown_stream")]
+    pub codec_type: StreamType,
+    #[serde(default)]
+    pub codec_name: String,
+    #[serde(default)]
+    pub codec_long_name: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub level: Option<i64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub bit_rate: Option<u64>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// Average frames per second.
+    #[serde(default, deserialize_with = "frame_rate")]
+    pub avg_frame_rate: Option<f64>,
+    #[serde(default)]
+    pub pix_fmt: Option<String>,
+    #[serde(default)]
+    pub color_transfer: Option<String>,
+    #[serde(default)]
+    pub color_primaries: Option<String>,
+    #[serde(default)]
+    pub color_space: Option<String>,
+    #[serde(default)]
+    pub color_range: Option<String>,
+    /// Derived from the transfer function and side data, see `MediaInfo::parse`.
+    #[serde(default)]
+    pub hdr: Option<HdrFormat>,
+    #[serde(default)]
+    pub channels: Option<u32>,
+    #[serde(default)]
+    pub channel_layout: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub sample_rate: Option<u32>,
+    #[serde(default)]
+    pub disposition: Disposition,
+    #[serde(default)]
+    pub tags: Tags,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub side_data_list: Vec<SideData>,
+}
+
+fn unknown_stream() -> StreamType {
+    StreamType::Unknown
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chapter {
+    #[serde(default, deserialize_with = "lenient")]
+    pub start_time: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub end_time: Option<f64>,
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+impl StreamInfo {
+    /// The Dolby Vision configuration of a video stream, if it has one.
+    pub fn dolby_vision(&self) -> Option<&SideData> {
+        self.side_data_list
+            .iter()
+            .find(|side_data| side_data.side_data_type.contains("DOVI"))
+    }
+
+    /// Dolby Vision with a compatible base layer, as in profiles 7 and 8,
+    /// is handled as that base layer, which the transfer describes.
+    fn detect_hdr(&self) -> Option<HdrFormat> {
+        if let Some(dovi) = self.dolby_vision() {
+            let compatible = match dovi.dv_bl_signal_compatibility_id {
+                Some(id) => id != 0,
+                None => dovi.dv_profile.is_some_and(|profile| profile != 5),
+            };
+            if !compatible {
+                return Some(HdrFormat::DolbyVision);
+            }
+        }
+        match self.color_transfer.as_deref() {
+            Some("smpte2084") => Some(HdrFormat::Hdr10),
+            Some("arib-std-b67") => Some(HdrFormat::Hlg),
+            _ => None,
+        }
+    }
+}
+
+impl MediaInfo {
+    pub fn parse(json: &[u8]) -> Result<Self, String> {
+        let mut info: MediaInfo = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+        for stream in &mut info.streams {
+            if stream.codec_type == StreamType::Video {
+                stream.hdr = stream.detect_hdr();
+            }
+        }
+        Ok(info)
+    }
+
+    /// The main video stream; cover art is not one.
+    pub fn video_stream(&self) -> Option<&StreamInfo> {
+        self.streams.iter().find(|stream| {
+            stream.codec_type == StreamType::Video && !stream.disposition.attached_pic
+        })
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use super::ffmpeg::FfmpegInfo;
+use super::probe::MediaInfo;
+use super::sidecar::{self, SidecarSubtitle};
+
+/// What probing a file found, before any per-request decisions.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProbeData {
+    pub info: Arc<MediaInfo>,
+    pub duration: f64,
+    /// Subtitle files that belong to the video, in track id order.
+    pub sidecar_subtitles: Vec<SidecarSubtitle>,
+}
+
+/// Changes to the cache within this long of each other are saved together.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// An entry whose file was found unchanged this recently is used without
+/// looking at the file again, so the many segment requests of a stream do
+/// not each stat the file and list its subtitle folders.
+const RESTAMP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Identifies the version of a file that was probed. The modification
+/// times of the directory and its subtitle folders change when subtitle
+/// files are added or removed, and those of the subtitle files when one is
+/// replaced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    mtime: u128,
+    size: u64,
+    dir_mtime: u128,
+    #[serde(default)]
+    subtitle_folders_mtime: Option<u128>,
+    /// Path, modification time and size of each sidecar subtitle.
+    #[serde(default)]
+    sidecars: Vec<(PathBuf, u128, u64)>,
+}
+
+/// Bumped when what is derived from ffprobe's output changes, so files are
+/// probed again.
+const VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(default)]
+    version: u32,
+    stamp: FileStamp,
+    data: Arc<ProbeData>,
+    /// When the file last matched `stamp`.
+    #[serde(skip)]
+    checked: Option<Instant>,
+}
+
+/// Probe results held in memory and persisted to the cache directory, so
+/// each file is probed once rather than on every request.
+struct ProbeCache {
+    file: PathBuf,
+    entries: Mutex<HashMap<String, Entry>>,
+    warming: AtomicBool,
+    /// Whether a save is already scheduled.
+    save_pending: AtomicBool,
+}
+
+static PROBES: LazyLock<ProbeCache> =
+    LazyLock::new(|| ProbeCache::load(super::cache_dir().join("probes.json")));
+
+fn modified_nanos(path: &Path) -> Option<u128> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+/// Latest modification of the subtitle folders next to a video, including
+/// the per-video folders inside them.
+fn subtitle_folders_mtime(dir: &Path) -> Option<u128> {
+    sidecar::subtitle_folders(dir)
+        .iter()
+        .flat_map(|folder| {
+            let inner = fs::read_dir(folder)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.is_dir());
+            std::iter::once(folder.clone()).chain(inner)
+        })
+        .filter_map(|folder: PathBuf| modified_nanos(&folder))
+        .max()
+}
+
+fn file_stamp(path: &str) -> Option<FileStamp> {
+    let path = Path::new(path);
+    let dir = path.parent()?;
+    Some(FileStamp {
+        mtime: modified_nanos(path)?,
+        size: fs::metadata(path).ok()?.len(),
+        dir_mtime: modified_nanos(dir)?,
+        subtitle_folders_mtime: subtitle_folders_mtime(dir),
+        sidecars: sidecar::paths(path)
+            .into_iter()
+            .filter_map(|sidecar| {
+                let mtime = modified_nanos(&sidecar)?;
+                let size = fs::metadata(&sidecar).ok()?.len();
+                Some((sidecar, mtime, size))
+            })
+            .collect(),
+    })
+}
+
+impl ProbeCache {
+    fn load(file: PathBuf) -> Self {
+        let entries = fs::read(&file)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        ProbeCache {
+            file,
+            entries: Mutex::new(entries),
+            warming: AtomicBool::new(false),
+            save_pending: AtomicBool::new(false),
+        }
+    }
+
+    fn get(&self, path: &str, stamp: &FileStamp) -> Option<Arc<ProbeData>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get_mut(path)
+            .filter(|entry| entry.version == VERSION && entry.stamp == *stamp)?;
+        entry.checked = Some(Instant::now());
+        Some(entry.data.clone())
+    }
+
+    /// The entry for a file that was found unchanged within the last
+    /// `RESTAMP_INTERVAL`, without stamping the file again.
+    fn recently_checked(&self, path: &str) -> Option<Arc<ProbeData>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(path)
+            .filter(|entry| entry.version == VERSION)
+            .filter(|entry| {
+                entry
+                    .checked
+                    .is_some_and(|at| at.elapsed() < RESTAMP_INTERVAL)
+            })
+            .map(|entry| entry.data.clone())
+    }
+
+    fn insert(&self, path: &str, stamp: FileStamp, data: Arc<ProbeData>) {
+        self.entries.lock().unwrap().insert(
+            path.to_string(),
+            Entry {
+                version: VERSION,
+                stamp,
+                data,
+                checked: Some(Instant::now()),
+            },
+        );
+    }
+
+    /// Schedules a save, off the request path. Saves are delayed a little
+    /// so a burst of probes is written once.
+    fn save(&'static self) {
+        if self.save_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        tokio::spawn(async move {
+            tokio::time::sleep(SAVE_DELAY).await;
+            // Changes from here on schedule another save.
+            self.save_pending.store(false, Ordering::Release);
+            let json = serde_json::to_vec(&*self.entries.lock().unwrap()).unwrap();
+            let written = tokio::task::spawn_blocking(move || {
+                let partial = self.file.with_extension("json.partial");
+                self.file
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| fs::write(&partial, json))
+                    .and_then(|_| fs::rename(&partial, &self.file))
+            })
+            .await;
+            if let Ok(Err(e)) = written {
+                eprintln!("Failed to save probe cache: {e}");
+            }
+        });
+    }
+}
+
+async fn run_ffprobe(ffmpeg: &FfmpegInfo, input_path: &str) -> Result<ProbeData, String> {
+    let output = Command::new(&ffmpeg.ffprobe_path)
+        .args(["-v", "error"])
+        .args(["-print_format", "json"])
+        .args(["-show_streams"])
+        .args(["-show_format"])
+        .args(["-show_chapters"])
+        .args([input_path])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to execute ffprobe: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ffprobe failed for {input_path} ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let info = MediaInfo::parse(&output.stdout)
+        .map_err(|e| format!("Failed to parse ffprobe output for {input_path}: {e}"))?;
+    let duration = info
+        .format
+        .duration
+        .ok_or_else(|| format!("ffprobe found no duration for {input_path}"))?;
+
+    Ok(ProbeData {
+        info: Arc::new(info),
+        duration,
+        sidecar_subtitles: sidecar::find(Path::new(input_path)),
+    })
+}
+
+async fn probe_and_store(
+    ffmpeg: &FfmpegInfo,
+    input_path: &str,
+    save: bool,
+) -> Result<Arc<ProbeData>, String> {
+    if let Some(data) = PROBES.recently_checked(input_path) {
+        return Ok(data);
+    }
+    let stamp = file_stamp(input_path).ok_or_else(|| format!("File not found: {input_path}"))?;
+    if let Some(data) = PROBES.get(input_path, &stamp) {
+        return Ok(data);
+    }
+    println!("Probing {input_path}");
+    let data = Arc::new(run_ffprobe(ffmpeg, input_path).await?);
+    PROBES.insert(input_path, stamp, data.clone());
+    if save {
+        PROBES.save();
+    }
+    Ok(data)
+}
+
+/// Probe results for a file, from the cache unless the file or its
+/// directory changed since it was last probed.
+pub async fn probe(ffmpeg: &FfmpegInfo, input_path: &str) -> Result<Arc<ProbeData>, String> {
+    probe_and_store(ffmpeg, input_path, true).await
+}
+
+/// Probes the given files in the background, one at a time, so later
+/// requests find them cached. Does nothing if a warm-up is already running.
+pub fn warm(ffmpeg: Arc<FfmpegInfo>, paths: Vec<String>) {
+    if PROBES.warming.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut probed = 0;
+        for path in paths {
+            match file_stamp(&path) {
+                Some(stamp) if PROBES.get(&path, &stamp).is_some() => continue,
+                _ => {}
+            }
+            match probe_and_store(&ffmpeg, &path, false).await {
+                Ok(_) => probed += 1,
+                Err(e) => eprintln!("Probe cache warm-up: {e}"),
+            }
+        }
+        if probed > 0 {
+            PROBES.save();
+            println!("Probe cache warm-up probed {probed} files");
+        }
+        PROBES.warming.store(false, Ordering::Release);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_dropped_when_the_file_or_its_subtitles_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let video = dir.path().join("Movie.mkv");
+        let video_path = video.to_str().unwrap();
+        fs::write(&video, "video").unwrap();
+        let cache = ProbeCache::load(dir.path().join("probes.json"));
+        let data = Arc::new(ProbeData {
+            info: Arc::new(MediaInfo::default()),
+            duration: 60.0,
+            sidecar_subtitles: Vec::new(),
+        });
+
+        let stamp = file_stamp(video_path).unwrap();
+        cache.insert(video_path, stamp.clone(), data.clone());
+        assert!(cache.get(video_path, &stamp).is_some());
+
+        // A new sidecar file.
+        let subtitle = dir.path().join("Movie.en.srt");
+        fs::write(&subtitle, "1").unwrap();
+        let with_subtitle = file_stamp(video_path).unwrap();
+        assert_eq!(with_subtitle.sidecars.len(), 1);
+        assert!(cache.get(video_path, &with_subtitle).is_none());
+
+        // The sidecar file replaced.
+        cache.insert(video_path, with_subtitle.clone(), data.clone());
+        fs::write(&subtitle, "replaced").unwrap();
+        let replaced = file_stamp(video_path).unwrap();
+        assert_ne!(replaced, with_subtitle);
+        assert!(cache.get(video_path, &replaced).is_none());
+
+        // The video itself changed.
+        cache.insert(video_path, replaced.clone(), data.clone());
+        fs::write(&video, "a new video").unwrap();
+        assert!(cache
+            .get(video_path, &file_stamp(video_path).unwrap())
+            .is_none());
+
+        // Entries from before a format change.
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(video_path)
+            .unwrap()
+            .version = VERSION - 1;
+        assert!(cache.get(video_path, &replaced).is_none());
+    }
+
+    #[test]
+    fn files_are_restamped_once_the_interval_is_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let video = dir.path().join("Movie.mkv");
+        let video_path = video.to_str().unwrap();
+        fs::write(&video, "video").unwrap();
+        let cache = ProbeCache::load(dir.path().join("probes.json"));
+        let data = Arc::new(ProbeData {
+            info: Arc::new(MediaInfo::default()),
+            duration: 60.0,
+            sidecar_subtitles: Vec::new(),
+        });
+
+        assert!(cache.recently_checked(video_path).is_none());
+        let stamp = file_stamp(video_path).unwrap();
+        cache.insert(video_path, stamp.clone(), data);
+        assert!(cache.recently_checked(video_path).is_some());
+
+        let set_checked = |checked| {
+            cache
+                .entries
+                .lock()
+                .unwrap()
+                .get_mut(video_path)
+                .unwrap()
+                .checked = checked;
+        };
+        set_checked(Instant::now().checked_sub(RESTAMP_INTERVAL));
+        assert!(cache.recently_checked(video_path).is_none());
+        // Loaded from disk, never checked.
+        set_checked(None);
+        assert!(cache.recently_checked(video_path).is_none());
+
+        // A match renews the entry.
+        assert!(cache.get(video_path, &stamp).is_some());
+        assert!(cache.recently_checked(video_path).is_some());
+    }
+}
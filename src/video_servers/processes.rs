@@ -0,0 +1,144 @@
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{response::IntoResponse, Json};
+use serde::Serialize;
+use tokio::{
+    process::{ChildStderr, ChildStdout, Command},
+    task::JoinHandle,
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+/// Counters for every ffmpeg process the server has started.
+struct ProcessStats {
+    spawned: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    killed: AtomicU64,
+}
+
+static STATS: ProcessStats = ProcessStats {
+    spawned: AtomicU64::new(0),
+    completed: AtomicU64::new(0),
+    failed: AtomicU64::new(0),
+    killed: AtomicU64::new(0),
+};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProcessCounts {
+    pub running: u64,
+    pub spawned: u64,
+    pub completed: u64,
+    pub failed: u64,
+    pub killed: u64,
+}
+
+pub fn process_counts() -> ProcessCounts {
+    let spawned = STATS.spawned.load(Ordering::Relaxed);
+    let completed = STATS.completed.load(Ordering::Relaxed);
+    let failed = STATS.failed.load(Ordering::Relaxed);
+    let killed = STATS.killed.load(Ordering::Relaxed);
+    ProcessCounts {
+        running: spawned.saturating_sub(completed + failed + killed),
+        spawned,
+        completed,
+        failed,
+        killed,
+    }
+}
+
+/// An ffmpeg process whose lifetime is tied to this handle. Dropping it
+/// before the process exits cancels its token, which kills and reaps the
+/// child, so abandoned requests never leave encoders running.
+pub struct TrackedProcess {
+    pub pid: Option<u32>,
+    pub stdout: Option<ChildStdout>,
+    /// Only there if the caller piped stderr.
+    pub stderr: Option<ChildStderr>,
+    exit: JoinHandle<Option<ExitStatus>>,
+    _guard: DropGuard,
+}
+
+impl TrackedProcess {
+    /// Waits for the process to exit on its own.
+    pub async fn wait(self) -> Option<ExitStatus> {
+        let TrackedProcess {
+            exit,
+            _guard: guard,
+            ..
+        } = self;
+        let status = exit.await.ok().flatten();
+        drop(guard);
+        status
+    }
+}
+
+/// Spawns `command` with stdout piped. Cancelling `cancel` also stops the
+/// process, so it can be shared by everything belonging to one request.
+pub fn spawn_tracked(
+    mut command: Command,
+    cancel: CancellationToken,
+) -> std::io::Result<TrackedProcess> {
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    let mut child = command.stdout(Stdio::piped()).kill_on_drop(true).spawn()?;
+    STATS.spawned.fetch_add(1, Ordering::Relaxed);
+    let pid = child.id();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let token = cancel.child_token();
+    let guard = token.clone().drop_guard();
+
+    let exit = tokio::spawn(async move {
+        tokio::select! {
+            status = child.wait() => {
+                match &status {
+                    Ok(status) if status.success() => {
+                        STATS.completed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(status) => {
+                        eprintln!("{program} exited with {status}");
+                        STATS.failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to wait for {program}: {e}");
+                        STATS.failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                status.ok()
+            }
+            _ = token.cancelled() => {
+                // The process may have finished just as we were cancelled.
+                if let Ok(Some(status)) = child.try_wait() {
+                    if status.success() {
+                        STATS.completed.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        STATS.failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return Some(status);
+                }
+                if let Err(e) = child.kill().await {
+                    eprintln!("Failed to kill {program} {pid:?}: {e}");
+                }
+                STATS.killed.fetch_add(1, Ordering::Relaxed);
+                println!("Killed abandoned {program} {pid:?}");
+                None
+            }
+        }
+    });
+
+    Ok(TrackedProcess {
+        pid,
+        stdout,
+        stderr,
+        exit,
+        _guard: guard,
+    })
+}
+
+pub async fn get_process_counts() -> impl IntoResponse {
+    Json(process_counts())
+}
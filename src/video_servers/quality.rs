@@ -0,0 +1,183 @@
+use serde::Serialize;
+
+/// A named output size with a bitrate cap, one rung of the ABR ladder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityProfile {
+    pub name: &'static str,
+    pub width: u32,
+    pub height: u32,
+    /// Video bitrate cap in kbit/s.
+    pub max_bitrate: u32,
+}
+
+/// Every profile, from the smallest to the largest.
+pub const PROFILES: [QualityProfile; 4] = [
+    QualityProfile {
+        name: "480p",
+        width: 854,
+        height: 480,
+        max_bitrate: 1_500,
+    },
+    QualityProfile {
+        name: "720p",
+        width: 1280,
+        height: 720,
+        max_bitrate: 4_000,
+    },
+    QualityProfile {
+        name: "1080p",
+        width: 1920,
+        height: 1080,
+        max_bitrate: 8_000,
+    },
+    QualityProfile {
+        name: "4k",
+        width: 3840,
+        height: 2160,
+        max_bitrate: 20_000,
+    },
+];
+
+/// Used when a request does not name a profile.
+pub const DEFAULT_QUALITY: &str = "1080p";
+
+/// A profile applied to a particular source.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rendition {
+    pub name: &'static str,
+    pub width: u32,
+    pub height: u32,
+    /// Video bitrate cap in kbit/s.
+    pub max_bitrate: u32,
+    /// Whether the output is smaller than the source.
+    pub scaled: bool,
+}
+
+pub fn find(name: &str) -> Option<QualityProfile> {
+    let name = name.to_ascii_lowercase();
+    let name = match name.as_str() {
+        "2160p" | "uhd" => "4k",
+        name => name,
+    };
+    PROFILES.into_iter().find(|profile| profile.name == name)
+}
+
+/// Rounds down to an even number, which 4:2:0 encoders require.
+fn even(value: f64) -> u32 {
+    ((value as u32) & !1).max(2)
+}
+
+impl QualityProfile {
+    /// This profile with its box shrunk to at most `max`, for clients that
+    /// cannot decode larger video.
+    pub fn within(&self, max: (u32, u32)) -> QualityProfile {
+        QualityProfile {
+            width: self.width.min(max.0),
+            height: self.height.min(max.1),
+            ..*self
+        }
+    }
+
+    /// Fits the source into this profile's box, keeping its aspect ratio and
+    /// never upscaling. Without a known source size, the box itself is used.
+    pub fn rendition(&self, source: Option<(u32, u32)>) -> Rendition {
+        let (width, height, scaled) = match source {
+            Some((source_width, source_height)) if source_width > 0 && source_height > 0 => {
+                let scale = (self.width as f64 / source_width as f64)
+                    .min(self.height as f64 / source_height as f64);
+                if scale < 1.0 {
+                    (
+                        even(source_width as f64 * scale),
+                        even(source_height as f64 * scale),
+                        true,
+                    )
+                } else {
+                    (even(source_width as f64), even(source_height as f64), false)
+                }
+            }
+            _ => (self.width, self.height, true),
+        };
+        Rendition {
+            name: self.name,
+            width,
+            height,
+            max_bitrate: self.max_bitrate,
+            scaled,
+        }
+    }
+}
+
+/// The renditions worth offering for a source, smallest first. Profiles
+/// that would come out at the same size as a smaller one are left out, so a
+/// 720p source gets 480p and 720p but no 1080p or 4k. No rendition is
+/// larger than `max`.
+pub fn ladder(source: Option<(u32, u32)>, max: (u32, u32)) -> Vec<Rendition> {
+    let mut ladder: Vec<Rendition> = Vec::new();
+    for profile in PROFILES {
+        let rendition = profile.within(max).rendition(source);
+        let duplicate = ladder
+            .iter()
+            .any(|other| (other.width, other.height) == (rendition.width, rendition.height));
+        if !duplicate {
+            ladder.push(rendition);
+        }
+    }
+    ladder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_LIMIT: (u32, u32) = (u32::MAX, u32::MAX);
+
+    fn sizes(ladder: &[Rendition]) -> Vec<(&str, u32, u32)> {
+        ladder
+            .iter()
+            .map(|rendition| (rendition.name, rendition.width, rendition.height))
+            .collect()
+    }
+
+    #[test]
+    fn a_720p_source_gets_no_larger_rungs() {
+        let ladder = ladder(Some((1280, 720)), NO_LIMIT);
+        assert_eq!(sizes(&ladder), [("480p", 852, 480), ("720p", 1280, 720)]);
+        assert!(ladder[0].scaled && !ladder[1].scaled);
+    }
+
+    #[test]
+    fn ultrawide_sources_are_fitted_by_their_width() {
+        let ladder = ladder(Some((3840, 1600)), NO_LIMIT);
+        assert_eq!(
+            sizes(&ladder),
+            [
+                ("480p", 854, 354),
+                ("720p", 1280, 532),
+                ("1080p", 1920, 800),
+                ("4k", 3840, 1600),
+            ]
+        );
+    }
+
+    #[test]
+    fn odd_sizes_come_out_even() {
+        let rendition = find("720p").unwrap().rendition(Some((1281, 721)));
+        assert_eq!((rendition.width, rendition.height), (1278, 720));
+        assert!(rendition.scaled);
+
+        let rendition = find("480p").unwrap().rendition(Some((853, 479)));
+        assert_eq!((rendition.width, rendition.height), (852, 478));
+        assert!(!rendition.scaled);
+
+        for rendition in ladder(Some((1917, 799)), (1279, 719)) {
+            assert!(rendition.width % 2 == 0 && rendition.height % 2 == 0);
+            assert!(rendition.width <= 1279 && rendition.height <= 719);
+        }
+    }
+
+    #[test]
+    fn without_a_source_size_the_box_is_used() {
+        let rendition = find("UHD").unwrap().rendition(None);
+        assert_eq!((rendition.width, rendition.height), (3840, 2160));
+    }
+}
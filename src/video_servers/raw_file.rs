@@ -0,0 +1,326 @@
+use std::io::SeekFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Query,
+    http::{status::StatusCode, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use hyper::header;
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+/// Requests asking for more ranges than this get the whole file instead.
+const MAX_RANGES: usize = 16;
+const BOUNDARY: &str = "NEXUS_FLIX_BYTERANGES";
+
+#[derive(Deserialize)]
+pub struct RawFileRequest {
+    pub path: String,
+}
+
+/// An inclusive byte range within the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self, file_len: u64) -> String {
+        format!("bytes {}-{}/{file_len}", self.start, self.end)
+    }
+}
+
+/// Parses a `Range` header into the satisfiable ranges. Returns `None` when
+/// the header is malformed, in which case it must be ignored.
+fn parse_range(value: &str, file_len: u64) -> Option<Vec<ByteRange>> {
+    let specs = value.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (first, last) = spec.trim().split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                if suffix == 0 || file_len == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: file_len.saturating_sub(suffix),
+                    end: file_len - 1,
+                }
+            }
+            (first, last) => {
+                let start: u64 = first.parse().ok()?;
+                let end = match last {
+                    "" => u64::MAX,
+                    last => last.parse().ok()?,
+                };
+                if end < start {
+                    return None;
+                }
+                if start >= file_len {
+                    continue;
+                }
+                ByteRange {
+                    start,
+                    end: end.min(file_len - 1),
+                }
+            }
+        };
+        ranges.push(range);
+    }
+    Some(ranges)
+}
+
+/// Strong validator built from the file size and modification time.
+fn entity_tag(file_len: u64, modified: SystemTime) -> String {
+    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "\"{file_len:x}-{:x}-{:x}\"",
+        modified.as_secs(),
+        modified.subsec_nanos()
+    )
+}
+
+/// HTTP dates have a resolution of one second.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Weak comparison of an `If-None-Match` list against our tag.
+fn none_match(value: &str, etag: &str) -> bool {
+    value.trim() == "*"
+        || value
+            .split(',')
+            .any(|tag| tag.trim().trim_start_matches("W/") == etag)
+}
+
+/// Whether the `Range` header should be honoured given `If-Range`. Entity
+/// tags must match strongly and dates exactly.
+fn if_range_matches(value: &str, etag: &str, last_modified: SystemTime) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') {
+        value == etag
+    } else {
+        httpdate::parse_http_date(value)
+            .map(|date| date == last_modified)
+            .unwrap_or(false)
+    }
+}
+
+async fn open_at(path: &str, start: u64) -> std::io::Result<File> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(file)
+}
+
+fn range_body(file: File, range: ByteRange) -> Body {
+    Body::from_stream(ReaderStream::new(file.take(range.len())))
+}
+
+fn multipart_body(path: String, ranges: Vec<ByteRange>, mime_type: String, file_len: u64) -> Body {
+    let parts = stream::iter(ranges).then(move |range| {
+        let path = path.clone();
+        let part_header = format!(
+            "\r\n--{BOUNDARY}\r\nContent-Type: {mime_type}\r\nContent-Range: {}\r\n\r\n",
+            range.content_range(file_len)
+        );
+        async move {
+            let file = open_at(&path, range.start).await?;
+            let header = stream::once(async move { Ok(Bytes::from(part_header)) });
+            let data = ReaderStream::new(file.take(range.len()));
+            Ok::<_, std::io::Error>(header.chain(data))
+        }
+    });
+    let closing = stream::once(async { Ok(Bytes::from(format!("\r\n--{BOUNDARY}--\r\n"))) });
+    Body::from_stream(parts.try_flatten().chain(closing))
+}
+
+fn plain_response(status: StatusCode, message: String) -> Response {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// Serves an original library file with full `Range` support, so external
+/// players and download tools can seek within it.
+pub async fn serve_raw_file(
+    Query(params): Query<RawFileRequest>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata = match tokio::fs::metadata(&params.path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => {
+            return plain_response(
+                StatusCode::NOT_FOUND,
+                format!("File not found: {}", params.path),
+            )
+        }
+    };
+    let file_len = metadata.len();
+    let last_modified = truncate_to_secs(metadata.modified().unwrap_or(UNIX_EPOCH));
+    let etag = entity_tag(file_len, metadata.modified().unwrap_or(UNIX_EPOCH));
+    let mime_type = match infer::get_from_path(&params.path) {
+        Ok(Some(mime)) => mime.mime_type().to_string(),
+        _ => "application/octet-stream".to_string(),
+    };
+
+    let builder = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(last_modified),
+        );
+
+    let not_modified = match header_str(&headers, header::IF_NONE_MATCH) {
+        Some(value) => none_match(value, &etag),
+        None => header_str(&headers, header::IF_MODIFIED_SINCE)
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .is_some_and(|since| last_modified <= since),
+    };
+    if not_modified {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let range_allowed = header_str(&headers, header::IF_RANGE)
+        .is_none_or(|value| if_range_matches(value, &etag, last_modified));
+    let ranges = header_str(&headers, header::RANGE)
+        .filter(|_| range_allowed)
+        .and_then(|value| parse_range(value, file_len))
+        .filter(|ranges| ranges.len() <= MAX_RANGES);
+
+    match ranges {
+        None => match open_at(&params.path, 0).await {
+            Ok(file) => builder
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, mime_type)
+                .header(header::CONTENT_LENGTH, file_len)
+                .body(range_body(
+                    file,
+                    ByteRange {
+                        start: 0,
+                        end: file_len.saturating_sub(1),
+                    },
+                ))
+                .unwrap(),
+            Err(e) => plain_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        },
+        Some(ranges) if ranges.is_empty() => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{file_len}"))
+            .body(Body::empty())
+            .unwrap(),
+        Some(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            match open_at(&params.path, range.start).await {
+                Ok(file) => builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_TYPE, mime_type)
+                    .header(header::CONTENT_RANGE, range.content_range(file_len))
+                    .header(header::CONTENT_LENGTH, range.len())
+                    .body(range_body(file, range))
+                    .unwrap(),
+                Err(e) => plain_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        }
+        Some(ranges) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={BOUNDARY}"),
+            )
+            .body(multipart_body(params.path, ranges, mime_type, file_len))
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parse_range_reads_closed_open_and_suffix_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(vec![range(0, 99)]));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(vec![range(900, 999)]));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(vec![range(900, 999)]));
+        // Ranges past the end are cut to the file.
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(vec![range(0, 999)]));
+        assert_eq!(
+            parse_range("bytes=990-5000", 1000),
+            Some(vec![range(990, 999)])
+        );
+    }
+
+    #[test]
+    fn parse_range_keeps_overlapping_ranges_in_order() {
+        assert_eq!(
+            parse_range("bytes=0-99, 50-149,-10", 1000),
+            Some(vec![range(0, 99), range(50, 149), range(990, 999)])
+        );
+    }
+
+    #[test]
+    fn parse_range_skips_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=0-0", 0), Some(vec![]));
+        assert_eq!(
+            parse_range("bytes=2000-2100,0-9", 1000),
+            Some(vec![range(0, 9)])
+        );
+    }
+
+    #[test]
+    fn parse_range_rejects_malformed_headers() {
+        assert_eq!(parse_range("items=0-99", 1000), None);
+        assert_eq!(parse_range("bytes=99-0", 1000), None);
+        assert_eq!(parse_range("bytes=abc-", 1000), None);
+        assert_eq!(parse_range("bytes=0-99,x", 1000), None);
+        assert_eq!(parse_range("bytes=-", 1000), None);
+    }
+
+    #[test]
+    fn if_range_needs_an_exact_match() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let etag = entity_tag(1000, modified);
+        assert!(if_range_matches(&etag, &etag, modified));
+        assert!(!if_range_matches(&format!("W/{etag}"), &etag, modified));
+        let date = httpdate::fmt_http_date(modified);
+        assert!(if_range_matches(&date, &etag, modified));
+        let later = httpdate::fmt_http_date(modified + Duration::from_secs(1));
+        assert!(!if_range_matches(&later, &etag, modified));
+        assert!(!if_range_matches("not a date", &etag, modified));
+    }
+}
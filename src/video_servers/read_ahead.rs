@@ -0,0 +1,273 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes, extract::Extension, http::status::StatusCode, response::IntoResponse, Json,
+};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use tokio_util::task::AbortOnDropHandle;
+
+use super::ffmpeg::FfmpegInfo;
+use super::probe_cache;
+use super::transcode_manager::TranscodeManager;
+use super::video_helpers::{self, ByteStream, ClientOptions};
+
+/// Counters for tuning how far ahead sessions transcode.
+struct ReadAheadStats {
+    started: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    discarded: AtomicU64,
+    skipped: AtomicU64,
+}
+
+static STATS: ReadAheadStats = ReadAheadStats {
+    started: AtomicU64::new(0),
+    hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+    discarded: AtomicU64::new(0),
+    skipped: AtomicU64::new(0),
+};
+
+#[derive(Serialize, Debug)]
+pub struct ReadAheadCounts {
+    /// Chunks transcoded ahead of each `/video` request.
+    pub chunks_ahead: usize,
+    pub started: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Chunks thrown away unused, usually because the player seeked.
+    pub discarded: u64,
+    /// Chunks not read ahead because no transcode slot was free.
+    pub skipped: u64,
+}
+
+/// A chunk transcoded, or still being transcoded, before it was requested.
+struct Chunk {
+    path: String,
+    start: f64,
+    duration: f64,
+    client: ClientOptions,
+    data: AbortOnDropHandle<Option<Bytes>>,
+}
+
+/// The chunks one session has read ahead, at most `read_ahead_chunks` of
+/// them. Dropping a chunk that is still in progress kills its ffmpeg.
+#[derive(Default)]
+pub struct ReadAheadCache {
+    chunks: Vec<Chunk>,
+}
+
+fn same_time(a: f64, b: f64) -> bool {
+    (a - b).abs() < 0.001
+}
+
+impl Chunk {
+    fn matches(&self, path: &str, start: f64, duration: f64, client: &ClientOptions) -> bool {
+        self.path == path
+            && same_time(self.start, start)
+            && same_time(self.duration, duration)
+            && self.client == *client
+    }
+}
+
+impl ReadAheadCache {
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn contains(&self, path: &str, start: f64, duration: f64, client: &ClientOptions) -> bool {
+        self.chunks
+            .iter()
+            .any(|chunk| chunk.matches(path, start, duration, client))
+    }
+
+    /// Takes the chunk a request asks for. Chunks before it have been played
+    /// past and are dropped; a request that was not read ahead is a seek or
+    /// a switch of quality or display, which discards everything.
+    fn take(
+        &mut self,
+        path: &str,
+        start: f64,
+        duration: f64,
+        client: &ClientOptions,
+    ) -> Option<AbortOnDropHandle<Option<Bytes>>> {
+        let found = self
+            .chunks
+            .iter()
+            .position(|chunk| chunk.matches(path, start, duration, client));
+        let chunk = found.map(|index| self.chunks.remove(index));
+        let before = self.chunks.len();
+        match chunk {
+            Some(_) => self
+                .chunks
+                .retain(|chunk| chunk.path == path && chunk.start > start),
+            None => self.chunks.clear(),
+        }
+        STATS
+            .discarded
+            .fetch_add((before - self.chunks.len()) as u64, Ordering::Relaxed);
+        chunk.map(|chunk| chunk.data)
+    }
+}
+
+/// Transcodes a whole chunk into memory, framed like a `/video` response.
+#[allow(clippy::too_many_arguments)]
+fn transcode_chunk(
+    ffmpeg: Arc<FfmpegInfo>,
+    manager: &TranscodeManager,
+    user: &str,
+    session: &str,
+    path: &str,
+    start: f64,
+    duration: f64,
+    client: ClientOptions,
+) -> Option<AbortOnDropHandle<Option<Bytes>>> {
+    let job = manager.try_start_read_ahead_job(user, session, path)?;
+    let path = path.to_string();
+    let task = tokio::spawn(async move {
+        let data = video_helpers::stream_video_data(ffmpeg, job, &path, start, duration, &client)
+            .await
+            .ok()?;
+        data.try_fold(Vec::new(), |mut buffer, chunk| {
+            buffer.extend_from_slice(&chunk);
+            future::ready(Ok(buffer))
+        })
+        .await
+        .ok()
+        .map(Bytes::from)
+    });
+    Some(AbortOnDropHandle::new(task))
+}
+
+/// Starts transcoding the chunks following `start` that are not cached yet,
+/// up to the end of the file.
+#[allow(clippy::too_many_arguments)]
+fn read_ahead(
+    manager: Arc<TranscodeManager>,
+    ffmpeg: Arc<FfmpegInfo>,
+    user: String,
+    session: String,
+    path: String,
+    start: f64,
+    duration: f64,
+    file_duration: f64,
+    client: ClientOptions,
+) {
+    for n in 1..=manager.read_ahead_chunks() {
+        let next = start + n as f64 * duration;
+        if next >= file_duration {
+            break;
+        }
+        let cached = manager.with_read_ahead(&user, &session, &path, |cache| {
+            cache.contains(&path, next, duration, &client)
+        });
+        if cached {
+            continue;
+        }
+        let Some(data) = transcode_chunk(
+            ffmpeg.clone(),
+            &manager,
+            &user,
+            &session,
+            &path,
+            next,
+            duration,
+            client.clone(),
+        ) else {
+            STATS.skipped.fetch_add(1, Ordering::Relaxed);
+            break;
+        };
+        STATS.started.fetch_add(1, Ordering::Relaxed);
+        manager.with_read_ahead(&user, &session, &path, |cache| {
+            cache.chunks.push(Chunk {
+                path: path.clone(),
+                start: next,
+                duration,
+                client: client.clone(),
+                data,
+            })
+        });
+    }
+}
+
+/// Returns the `/video` response for a chunk, from the session's read-ahead
+/// if it is there. Once the response has been sent in full, the chunks after
+/// it are transcoded in the background, with the same options. Errors carry
+/// the status to respond with.
+#[allow(clippy::too_many_arguments)]
+pub async fn video_chunk(
+    manager: Arc<TranscodeManager>,
+    ffmpeg: Arc<FfmpegInfo>,
+    user: &str,
+    session: &str,
+    path: &str,
+    start: f64,
+    duration: f64,
+    client: &ClientOptions,
+) -> Result<ByteStream, (StatusCode, String)> {
+    let cached = manager.with_read_ahead(user, session, path, |cache| {
+        cache.take(path, start, duration, client)
+    });
+    let cached = match cached {
+        Some(data) => data.await.ok().flatten(),
+        None => None,
+    };
+
+    let data = match cached {
+        Some(data) => {
+            STATS.hits.fetch_add(1, Ordering::Relaxed);
+            manager.update_position(user, session, path, start);
+            stream::once(future::ready(Ok(data))).boxed()
+        }
+        None => {
+            STATS.misses.fetch_add(1, Ordering::Relaxed);
+            let job = manager
+                .start_job(user, session, path, start, true)
+                .await
+                .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e))?;
+            video_helpers::stream_video_data(ffmpeg.clone(), job, path, start, duration, client)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        }
+    };
+
+    if manager.read_ahead_chunks() == 0 {
+        return Ok(data);
+    }
+    let (user, session, path) = (user.to_string(), session.to_string(), path.to_string());
+    let client = client.clone();
+    let next = stream::once(async move {
+        // Already probed for this request, so this hits the probe cache.
+        let Ok(probe) = probe_cache::probe(&ffmpeg, &path).await else {
+            return;
+        };
+        read_ahead(
+            manager,
+            ffmpeg,
+            user,
+            session,
+            path,
+            start,
+            duration,
+            probe.duration,
+            client,
+        );
+    })
+    .filter_map(|_| future::ready(None));
+    Ok(data.chain(next).boxed())
+}
+
+pub async fn get_read_ahead_stats(
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+) -> impl IntoResponse {
+    Json(ReadAheadCounts {
+        chunks_ahead: manager.read_ahead_chunks(),
+        started: STATS.started.load(Ordering::Relaxed),
+        hits: STATS.hits.load(Ordering::Relaxed),
+        misses: STATS.misses.load(Ordering::Relaxed),
+        discarded: STATS.discarded.load(Ordering::Relaxed),
+        skipped: STATS.skipped.load(Ordering::Relaxed),
+    })
+}
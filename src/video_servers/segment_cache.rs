@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::Bytes,
+    extract::{Extension, Query},
+    http::status::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use fnv::FnvHasher;
+use serde::{Deserialize, Serialize};
+use tempfile::TempPath;
+use tokio::{fs::File, io::AsyncWriteExt, process::Command};
+
+use super::Config;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Changes to the index within this long of each other are saved together.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Identifies one transcoded track segment. The profile is a hash of the
+/// ffmpeg arguments, so any change to the encoding settings is a new key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SegmentKey {
+    pub path: String,
+    /// Modification time (seconds since the epoch) and size of the source
+    /// when the segment was made.
+    pub mtime: u64,
+    pub source_size: u64,
+    pub track: String,
+    pub start: f64,
+    pub duration: f64,
+    pub profile: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheEntry {
+    #[serde(flatten)]
+    pub key: SegmentKey,
+    pub size: u64,
+    /// Milliseconds since the epoch, for LRU eviction.
+    pub last_access: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheStatus {
+    pub max_bytes: u64,
+    pub total_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: Vec<CacheEntry>,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, CacheEntry>,
+    /// Names of the entries of each source file.
+    by_path: HashMap<String, HashSet<String>>,
+    total_bytes: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Persistent cache of transcoded segments in the cache directory, capped at
+/// `segment_cache_max_mb` with least recently used segments evicted first.
+pub struct SegmentCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Arc<Mutex<Index>>,
+    /// Whether a save of the index is already scheduled.
+    save_pending: Arc<AtomicBool>,
+}
+
+/// A segment being written straight into the cache directory. Its file is
+/// removed when dropped, unless it was handed to `SegmentCache::put_file`.
+pub struct PartialSegment {
+    file: File,
+    path: TempPath,
+    size: u64,
+}
+
+impl PartialSegment {
+    pub async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data).await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl Index {
+    fn insert(&mut self, name: String, entry: CacheEntry) {
+        self.remove(&name);
+        self.total_bytes += entry.size;
+        self.by_path
+            .entry(entry.key.path.clone())
+            .or_default()
+            .insert(name.clone());
+        self.entries.insert(name, entry);
+    }
+
+    /// Forgets an entry; its file is left for the caller to delete.
+    fn remove(&mut self, name: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(name)?;
+        self.total_bytes -= entry.size;
+        if let Some(names) = self.by_path.get_mut(&entry.key.path) {
+            names.remove(name);
+            if names.is_empty() {
+                self.by_path.remove(&entry.key.path);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Forgets the entries of `path`, or all of them, and returns their names.
+    fn remove_path(&mut self, path: Option<&str>) -> Vec<String> {
+        let names: Vec<String> = match path {
+            Some(path) => self
+                .by_path
+                .get(path)
+                .map(|names| names.iter().cloned().collect())
+                .unwrap_or_default(),
+            None => self.entries.keys().cloned().collect(),
+        };
+        for name in &names {
+            self.remove(name);
+        }
+        names
+    }
+
+    /// Forgets the entries made from an older version of the source and
+    /// returns their names.
+    fn invalidate_stale(&mut self, key: &SegmentKey) -> Vec<String> {
+        let Some(names) = self.by_path.get(&key.path) else {
+            return Vec::new();
+        };
+        let stale: Vec<String> = names
+            .iter()
+            .filter(|name| {
+                self.entries.get(*name).is_some_and(|entry| {
+                    entry.key.mtime != key.mtime || entry.key.source_size != key.source_size
+                })
+            })
+            .cloned()
+            .collect();
+        for name in &stale {
+            self.remove(name);
+        }
+        stale
+    }
+}
+
+impl SegmentKey {
+    /// Builds the key for the output of `command` over a range of `path`.
+    /// Returns `None` if the source cannot be read.
+    pub async fn new(
+        path: &str,
+        track: String,
+        start: f64,
+        duration: f64,
+        command: &Command,
+    ) -> Option<Self> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        let mtime = metadata
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut hasher = FnvHasher::default();
+        command.as_std().get_program().hash(&mut hasher);
+        for arg in command.as_std().get_args() {
+            arg.hash(&mut hasher);
+        }
+        Some(SegmentKey {
+            path: path.to_string(),
+            mtime,
+            source_size: metadata.len(),
+            track,
+            start,
+            duration,
+            profile: format!("{:016x}", hasher.finish()),
+        })
+    }
+
+    fn file_name(&self) -> String {
+        let mut hasher = FnvHasher::default();
+        self.path.hash(&mut hasher);
+        self.mtime.hash(&mut hasher);
+        self.source_size.hash(&mut hasher);
+        self.track.hash(&mut hasher);
+        self.start.to_bits().hash(&mut hasher);
+        self.duration.to_bits().hash(&mut hasher);
+        self.profile.hash(&mut hasher);
+        format!("{:016x}.bin", hasher.finish())
+    }
+}
+
+impl SegmentCache {
+    pub fn new(config: &Config, dir: PathBuf) -> Self {
+        let mut index = Index::default();
+        if let Ok(data) = fs::read(dir.join(INDEX_FILE)) {
+            let entries: HashMap<String, CacheEntry> =
+                serde_json::from_slice(&data).unwrap_or_default();
+            for (name, entry) in entries {
+                // Segments whose file is gone cannot be served.
+                if dir.join(&name).is_file() {
+                    index.insert(name, entry);
+                }
+            }
+        }
+        SegmentCache {
+            dir,
+            max_bytes: config.segment_cache_max_mb * 1024 * 1024,
+            index: Arc::new(Mutex::new(index)),
+            save_pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    /// Schedules a save of the index, off the request path. Saves are
+    /// delayed a little so a burst of changes is written once.
+    fn save_index(&self) {
+        if self.save_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let dir = self.dir.clone();
+        let index = self.index.clone();
+        let save_pending = self.save_pending.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(SAVE_DELAY).await;
+            // Changes from here on schedule another save.
+            save_pending.store(false, Ordering::Release);
+            let data = serde_json::to_vec(&index.lock().unwrap().entries).unwrap();
+            let saved = tokio::task::spawn_blocking(move || {
+                let partial = dir.join(format!("{INDEX_FILE}.partial"));
+                fs::create_dir_all(&dir)
+                    .and_then(|_| fs::write(&partial, data))
+                    .and_then(|_| fs::rename(&partial, dir.join(INDEX_FILE)))
+            })
+            .await;
+            if let Ok(Err(e)) = saved {
+                eprintln!("Failed to save segment cache index: {e}");
+            }
+        });
+    }
+
+    /// Deletes the files of entries already removed from the index. Called
+    /// without the index lock held.
+    async fn delete_files(&self, names: Vec<String>) {
+        for name in names {
+            let _ = tokio::fs::remove_file(self.dir.join(name)).await;
+        }
+    }
+
+    pub async fn get(&self, key: &SegmentKey) -> Option<Bytes> {
+        if !self.enabled() {
+            return None;
+        }
+        let name = key.file_name();
+        let (cached, stale) = {
+            let mut index = self.index.lock().unwrap();
+            let stale = index.invalidate_stale(key);
+            let cached = index.entries.contains_key(&name);
+            if cached {
+                index.hits += 1;
+            } else {
+                index.misses += 1;
+            }
+            (cached, stale)
+        };
+        if !stale.is_empty() {
+            println!("Source changed, dropped cached segments of {}", key.path);
+            self.save_index();
+            self.delete_files(stale).await;
+        }
+        if !cached {
+            return None;
+        }
+        match tokio::fs::read(self.dir.join(&name)).await {
+            Ok(data) => {
+                if let Some(entry) = self.index.lock().unwrap().entries.get_mut(&name) {
+                    entry.last_access = now_millis();
+                }
+                Some(Bytes::from(data))
+            }
+            Err(_) => {
+                self.index.lock().unwrap().remove(&name);
+                self.delete_files(vec![name]).await;
+                None
+            }
+        }
+    }
+
+    /// Stores a segment, evicting the least recently used ones to stay
+    /// under the size cap.
+    pub async fn put(&self, key: SegmentKey, data: &[u8]) {
+        let size = data.len() as u64;
+        if !self.enabled() || data.is_empty() || size > self.max_bytes {
+            return;
+        }
+        let name = key.file_name();
+        let written = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(self.dir.join(&name), data).await
+        };
+        if let Err(e) = written.await {
+            eprintln!("Failed to write cached segment: {e}");
+            return;
+        }
+        self.add(name, key, size).await;
+    }
+
+    /// Starts a segment that is streamed into the cache as it is produced,
+    /// rather than collected in memory first.
+    pub async fn create_partial(&self) -> Option<PartialSegment> {
+        if !self.enabled() {
+            return None;
+        }
+        let dir = self.dir.clone();
+        let created = tokio::task::spawn_blocking(move || {
+            fs::create_dir_all(&dir)?;
+            tempfile::Builder::new()
+                .suffix(".partial")
+                .tempfile_in(&dir)
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|created| created);
+        match created {
+            Ok(temp) => {
+                let (file, path) = temp.into_parts();
+                Some(PartialSegment {
+                    file: File::from_std(file),
+                    path,
+                    size: 0,
+                })
+            }
+            Err(e) => {
+                eprintln!("Failed to create cached segment: {e}");
+                None
+            }
+        }
+    }
+
+    /// Stores a segment written with `create_partial`, like `put`.
+    pub async fn put_file(&self, key: SegmentKey, mut partial: PartialSegment) {
+        if partial.size == 0 || partial.size > self.max_bytes {
+            return;
+        }
+        if let Err(e) = partial.file.flush().await {
+            eprintln!("Failed to write cached segment: {e}");
+            return;
+        }
+        let name = key.file_name();
+        let PartialSegment { file, path, size } = partial;
+        drop(file);
+        let target = self.dir.join(&name);
+        let renamed = tokio::task::spawn_blocking(move || path.persist(target))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|persisted| persisted.map_err(|e| e.error));
+        if let Err(e) = renamed {
+            eprintln!("Failed to save cached segment: {e}");
+            return;
+        }
+        self.add(name, key, size).await;
+    }
+
+    /// Indexes a segment whose file is in place, evicting the least
+    /// recently used ones to stay under the size cap.
+    async fn add(&self, name: String, key: SegmentKey, size: u64) {
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            // Replacing an entry keeps its file, which was just rewritten.
+            index.insert(
+                name.clone(),
+                CacheEntry {
+                    key,
+                    size,
+                    last_access: now_millis(),
+                },
+            );
+            while index.total_bytes > self.max_bytes {
+                let oldest = index
+                    .entries
+                    .iter()
+                    .filter(|(other, _)| **other != name)
+                    .min_by_key(|(_, entry)| entry.last_access)
+                    .map(|(other, _)| other.clone());
+                match oldest {
+                    Some(oldest) => {
+                        index.remove(&oldest);
+                        evicted.push(oldest);
+                    }
+                    None => break,
+                }
+            }
+        }
+        self.save_index();
+        self.delete_files(evicted).await;
+    }
+
+    /// Deletes the cached segments of `path`, or everything. Returns how
+    /// many segments were removed.
+    pub async fn purge(&self, path: Option<&str>) -> usize {
+        let names = self.index.lock().unwrap().remove_path(path);
+        let removed = names.len();
+        self.save_index();
+        self.delete_files(names).await;
+        removed
+    }
+
+    pub fn status(&self, path: Option<&str>) -> CacheStatus {
+        let index = self.index.lock().unwrap();
+        let mut entries: Vec<CacheEntry> = index
+            .entries
+            .values()
+            .filter(|entry| path.is_none_or(|path| entry.key.path == path))
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_access));
+        CacheStatus {
+            max_bytes: self.max_bytes,
+            total_bytes: index.total_bytes,
+            hits: index.hits,
+            misses: index.misses,
+            entries,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CacheRequest {
+    pub path: Option<String>,
+}
+
+pub async fn get_segment_cache(
+    Extension(cache): Extension<Arc<SegmentCache>>,
+    Query(params): Query<CacheRequest>,
+) -> impl IntoResponse {
+    Json(cache.status(params.path.as_deref()))
+}
+
+pub async fn purge_segment_cache(
+    Extension(cache): Extension<Arc<SegmentCache>>,
+    Query(params): Query<CacheRequest>,
+) -> impl IntoResponse {
+    let removed = cache.purge(params.path.as_deref()).await;
+    println!("Purged {removed} cached segments");
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({ "removed": removed })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, mtime: u64, track: &str) -> CacheEntry {
+        CacheEntry {
+            key: SegmentKey {
+                path: path.to_string(),
+                mtime,
+                source_size: 100,
+                track: track.to_string(),
+                start: 0.0,
+                duration: 10.0,
+                profile: String::new(),
+            },
+            size: 10,
+            last_access: 0,
+        }
+    }
+
+    #[test]
+    fn invalidate_stale_only_drops_older_versions_of_the_file() {
+        let mut index = Index::default();
+        index.insert("a1".to_string(), entry("a.mkv", 1, "video"));
+        index.insert("a2".to_string(), entry("a.mkv", 2, "audio:0"));
+        index.insert("b1".to_string(), entry("b.mkv", 1, "video"));
+
+        let stale = index.invalidate_stale(&entry("a.mkv", 2, "video").key);
+        assert_eq!(stale, vec!["a1".to_string()]);
+        assert_eq!(index.total_bytes, 20);
+        assert!(index.entries.contains_key("a2") && index.entries.contains_key("b1"));
+
+        assert_eq!(index.remove_path(Some("a.mkv")), vec!["a2".to_string()]);
+        assert!(!index.by_path.contains_key("a.mkv"));
+        assert_eq!(index.total_bytes, 10);
+    }
+}
@@ -0,0 +1,316 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::charset;
+use super::languages;
+
+/// Subtitle formats ffmpeg can read from a separate file.
+const SUBTITLE_EXTENSIONS: [&str; 4] = ["srt", "vtt", "ass", "ssa"];
+const VIDEO_EXTENSIONS: [&str; 8] = ["mkv", "mp4", "m4v", "mov", "avi", "webm", "ts", "wmv"];
+/// Folders release groups put subtitles in, next to the video.
+const SUBTITLE_FOLDERS: [&str; 2] = ["subs", "subtitles"];
+
+/// Language names seen in subtitle file names, with their ISO 639-2/B codes.
+const LANGUAGE_NAMES: [(&str, &str); 20] = [
+    ("english", "eng"),
+    ("french", "fre"),
+    ("german", "ger"),
+    ("spanish", "spa"),
+    ("italian", "ita"),
+    ("portuguese", "por"),
+    ("dutch", "dut"),
+    ("russian", "rus"),
+    ("japanese", "jpn"),
+    ("chinese", "chi"),
+    ("korean", "kor"),
+    ("arabic", "ara"),
+    ("swedish", "swe"),
+    ("norwegian", "nor"),
+    ("danish", "dan"),
+    ("finnish", "fin"),
+    ("polish", "pol"),
+    ("turkish", "tur"),
+    ("hebrew", "heb"),
+    ("greek", "gre"),
+];
+
+/// A subtitle file that belongs to a video.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SidecarSubtitle {
+    pub path: PathBuf,
+    /// Lowercase file extension, e.g. `srt`.
+    pub format: String,
+    pub language: Option<String>,
+    pub forced: bool,
+    /// SDH, CC or HI: includes descriptions of sounds.
+    pub hearing_impaired: bool,
+    /// Detected encoding of the text, if it is not UTF-8.
+    pub encoding: Option<String>,
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn file_stem(path: &Path) -> Option<&str> {
+    path.file_stem()?.to_str()
+}
+
+/// What follows the video's name in a subtitle's name, e.g. `en.forced`
+/// for `Movie.en.forced.srt` and `Movie`, or `None` if it is not named
+/// after the video at all.
+fn name_suffix<'a>(subtitle_stem: &'a str, video_stem: &str) -> Option<&'a str> {
+    let rest = subtitle_stem.strip_prefix(video_stem)?;
+    match rest.strip_prefix('.') {
+        Some(suffix) => Some(suffix),
+        None if rest.is_empty() => Some(rest),
+        None => None,
+    }
+}
+
+fn parse(path: PathBuf, suffix: &str) -> SidecarSubtitle {
+    let mut language = None;
+    let mut forced = false;
+    let mut hearing_impaired = false;
+    let mut hi = false;
+    for token in suffix.split(['.', '_', '-', ' ']) {
+        let token = token.to_ascii_lowercase();
+        match token.as_str() {
+            "forced" | "foreign" => forced = true,
+            "sdh" | "cc" => hearing_impaired = true,
+            "hi" => hi = true,
+            _ if language.is_some() => {}
+            _ => {
+                language = languages::bibliographic(&token)
+                    .or_else(|| {
+                        LANGUAGE_NAMES
+                            .iter()
+                            .find(|(name, _)| *name == token)
+                            .map(|(_, code)| *code)
+                    })
+                    .map(str::to_string)
+            }
+        }
+    }
+    // `hi` is Hindi, unless another language is given, as in `en.hi`.
+    if hi {
+        match language {
+            Some(_) => hearing_impaired = true,
+            None => language = Some("hin".to_string()),
+        }
+    }
+    let format = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_ascii_lowercase();
+    SidecarSubtitle {
+        encoding: charset::detect_file(&path),
+        path,
+        format,
+        language,
+        forced,
+        hearing_impaired,
+    }
+}
+
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect()
+}
+
+/// Subtitle folders next to the video, e.g. `Subs/`.
+pub fn subtitle_folders(video_dir: &Path) -> Vec<PathBuf> {
+    files_in(video_dir)
+        .into_iter()
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        SUBTITLE_FOLDERS.contains(&name.to_ascii_lowercase().as_str())
+                    })
+        })
+        .collect()
+}
+
+/// The subtitle files that belong to a video, with the part of their name
+/// that describes them. A file belongs to the video if
+/// - it is next to the video or in a `Subs/` folder and named after it,
+///   like `Movie.srt` or `Movie.en.forced.srt`,
+/// - it is in `Subs/<video name>/`, like `Subs/Episode 1/2_English.srt`, or
+/// - it is in `Subs/` and the video is the only one in its folder.
+fn matching_files(video: &Path) -> Vec<(PathBuf, String)> {
+    let (Some(video_dir), Some(video_stem)) = (video.parent(), file_stem(video)) else {
+        return Vec::new();
+    };
+    let is_subtitle = |path: &Path| path.is_file() && has_extension(path, &SUBTITLE_EXTENSIONS);
+    let named_after_video = |path: &Path| {
+        file_stem(path)
+            .and_then(|stem| name_suffix(stem, video_stem))
+            .map(str::to_string)
+    };
+    let mut subtitles = Vec::new();
+    for path in files_in(video_dir) {
+        if is_subtitle(&path) {
+            if let Some(suffix) = named_after_video(&path) {
+                subtitles.push((path, suffix));
+            }
+        }
+    }
+
+    let only_video = files_in(video_dir)
+        .iter()
+        .filter(|path| path.is_file() && has_extension(path, &VIDEO_EXTENSIONS))
+        .count()
+        == 1;
+    for folder in subtitle_folders(video_dir) {
+        for path in files_in(&folder) {
+            if is_subtitle(&path) {
+                if let Some(suffix) = named_after_video(&path) {
+                    subtitles.push((path, suffix));
+                } else if only_video {
+                    let stem = file_stem(&path).unwrap_or_default().to_string();
+                    subtitles.push((path, stem));
+                }
+            } else if path.is_dir() && path.file_name() == video.file_stem() {
+                for path in files_in(&path) {
+                    if is_subtitle(&path) {
+                        let stem = file_stem(&path).unwrap_or_default().to_string();
+                        subtitles.push((path, stem));
+                    }
+                }
+            }
+        }
+    }
+    subtitles.sort_by(|a, b| a.0.cmp(&b.0));
+    subtitles
+}
+
+/// The paths of the subtitle files that belong to a video, without reading
+/// them.
+pub fn paths(video: &Path) -> Vec<PathBuf> {
+    matching_files(video)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// The subtitle files that belong to a video (see `matching_files`), sorted
+/// by path so a file keeps its position, and with it its track id, while
+/// the folder is unchanged.
+pub fn find(video: &Path) -> Vec<SidecarSubtitle> {
+    matching_files(video)
+        .into_iter()
+        .map(|(path, suffix)| parse(path, &suffix))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(suffix: &str) -> SidecarSubtitle {
+        parse(PathBuf::from(format!("Movie.{suffix}.srt")), suffix)
+    }
+
+    #[test]
+    fn name_suffix_needs_the_video_name_up_to_a_dot() {
+        assert_eq!(name_suffix("Movie.en.forced", "Movie"), Some("en.forced"));
+        assert_eq!(name_suffix("Movie", "Movie"), Some(""));
+        assert_eq!(name_suffix("Movie 2.en", "Movie"), None);
+        assert_eq!(name_suffix("Other.en", "Movie"), None);
+    }
+
+    #[test]
+    fn parse_reads_codes_names_and_flags() {
+        let subtitle = parsed("en.forced");
+        assert_eq!(subtitle.language.as_deref(), Some("eng"));
+        assert!(subtitle.forced && !subtitle.hearing_impaired);
+
+        let subtitle = parsed("German.SDH");
+        assert_eq!(subtitle.language.as_deref(), Some("ger"));
+        assert!(subtitle.hearing_impaired);
+
+        assert_eq!(parsed("pt-BR").language.as_deref(), Some("por"));
+        assert_eq!(parsed("xx").language, None);
+        assert_eq!(parsed("en").format, "srt");
+    }
+
+    #[test]
+    fn parse_gives_every_language_as_a_bibliographic_code() {
+        for suffix in ["de", "ger", "deu", "German", "DE.forced"] {
+            assert_eq!(parsed(suffix).language.as_deref(), Some("ger"), "{suffix}");
+        }
+        for suffix in ["fr", "fre", "fra", "french"] {
+            assert_eq!(parsed(suffix).language.as_deref(), Some("fre"), "{suffix}");
+        }
+        assert_eq!(parsed("zh-Hant").language.as_deref(), Some("chi"));
+        assert_eq!(parsed("nld.sdh").language.as_deref(), Some("dut"));
+    }
+
+    #[test]
+    fn lone_hi_is_hindi_and_hi_after_a_language_is_hearing_impaired() {
+        let subtitle = parsed("hi");
+        assert_eq!(subtitle.language.as_deref(), Some("hin"));
+        assert!(!subtitle.hearing_impaired);
+
+        let subtitle = parsed("en.hi");
+        assert_eq!(subtitle.language.as_deref(), Some("eng"));
+        assert!(subtitle.hearing_impaired);
+
+        let subtitle = parsed("hi.eng");
+        assert_eq!(subtitle.language.as_deref(), Some("eng"));
+        assert!(subtitle.hearing_impaired);
+    }
+
+    #[test]
+    fn matching_files_finds_named_and_folder_subtitles() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let subs = root.join("Subs");
+        fs::create_dir_all(subs.join("Movie")).unwrap();
+        for file in [
+            "Movie.mkv",
+            "Movie.en.srt",
+            "Movie 2.en.srt",
+            "Subs/Movie.fr.ass",
+            "Subs/2_English.srt",
+            "Subs/Movie/3_Spanish.srt",
+            "Subs/Movie/notes.txt",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let found = matching_files(&root.join("Movie.mkv"));
+        // Sorted by path, component by component.
+        let expected = [
+            (root.join("Movie.en.srt"), "en"),
+            (subs.join("2_English.srt"), "2_English"),
+            (subs.join("Movie/3_Spanish.srt"), "3_Spanish"),
+            (subs.join("Movie.fr.ass"), "fr"),
+        ];
+        assert_eq!(
+            found,
+            expected
+                .map(|(path, suffix)| (path, suffix.to_string()))
+                .to_vec()
+        );
+
+        // With another video next to it, unnamed files in `Subs/` could be
+        // either one's.
+        fs::write(root.join("Other.mkv"), "").unwrap();
+        let found = paths(&root.join("Movie.mkv"));
+        assert!(!found.contains(&subs.join("2_English.srt")));
+        assert_eq!(found.len(), 3);
+    }
+}
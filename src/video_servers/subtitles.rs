@@ -0,0 +1,297 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Extension, Path as UrlPath, Query},
+    http::status::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::header;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use super::charset;
+use super::device_profile::DeviceProfile;
+use super::ffmpeg::FfmpegInfo;
+use super::hls::error_response;
+use super::ocr::{self, OcrStatus};
+use super::probe::StreamType;
+use super::probe_cache;
+use super::segment_cache::SegmentKey;
+use super::transcode_manager::{request_user, TranscodeJob, TranscodeManager};
+use super::video_helpers::{self, run_to_end};
+use crate::api_servers;
+
+/// Seconds a client should wait before asking again for a track being read
+/// by OCR.
+const OCR_RETRY_SECS: u64 = 10;
+
+/// Text formats a whole subtitle track can be converted to.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleFormat {
+    #[default]
+    Vtt,
+    Srt,
+    Ass,
+}
+
+impl SubtitleFormat {
+    /// ffmpeg encoder and muxer name.
+    fn ffmpeg_name(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Vtt => "webvtt",
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::Ass => "ass",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Vtt => "text/vtt; charset=utf-8",
+            SubtitleFormat::Srt => "application/x-subrip; charset=utf-8",
+            SubtitleFormat::Ass => "text/x-ssa; charset=utf-8",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Vtt => "vtt",
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::Ass => "ass",
+        }
+    }
+}
+
+/// Converts a whole subtitle track, read from `sidecar` if it is a separate
+/// file, with every cue moved `offset` seconds later. With `copy`, the track
+/// is already in `format` and is written unchanged, so ASS keeps every
+/// styling detail.
+fn track_command(
+    ffmpeg: &FfmpegInfo,
+    path: &str,
+    id: u64,
+    sidecar: Option<&Path>,
+    format: SubtitleFormat,
+    copy: bool,
+    offset: f64,
+) -> Command {
+    let mut command = Command::new(&ffmpeg.ffmpeg_path);
+    command
+        .args(["-v", "error"])
+        .args(["-itsoffset", &offset.to_string()]);
+    let stream = match sidecar {
+        Some(sub_path) => {
+            command.args(["-i".as_ref(), sub_path.as_os_str()]);
+            "0:s:0".to_string()
+        }
+        None => {
+            command.args(["-i", path]);
+            format!("0:s:{id}")
+        }
+    };
+    command
+        .args(["-map", &stream])
+        .args(["-c:s", if copy { "copy" } else { format.ffmpeg_name() }])
+        .args(["-f", format.ffmpeg_name()])
+        .args(["pipe:1"]);
+    command
+}
+
+/// A whole subtitle track in `format`. Conversions go through the segment
+/// cache, keyed on the file the track is read from, so an edited sidecar is
+/// converted again.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_track(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    path: &str,
+    id: u64,
+    sidecar: Option<&Path>,
+    format: SubtitleFormat,
+    copy: bool,
+    offset: f64,
+) -> Result<Vec<u8>, String> {
+    let command = track_command(ffmpeg, path, id, sidecar, format, copy, offset);
+    let source = match sidecar {
+        Some(sub_path) => sub_path.to_string_lossy().to_string(),
+        None => path.to_string(),
+    };
+    let key = SegmentKey::new(
+        &source,
+        format!("subtitle-track:{id}"),
+        offset,
+        0.0,
+        &command,
+    )
+    .await;
+    run_to_end(job, command, key).await
+}
+
+#[derive(Deserialize)]
+pub struct SubtitleTrackRequest {
+    pub path: String,
+    /// `vtt` (the default), `srt` or `ass`.
+    pub format: Option<SubtitleFormat>,
+    /// Seconds to move every cue by; negative shows them earlier.
+    pub offset: Option<f64>,
+}
+
+/// `GET /subtitles/{track}`: a whole subtitle track, embedded or sidecar,
+/// as one file.
+pub async fn serve_subtitle_track(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    UrlPath(track): UrlPath<u64>,
+    Query(params): Query<SubtitleTrackRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let metadata =
+        match video_helpers::get_video_metadata(&ffmpeg, &params.path, &DeviceProfile::default())
+            .await
+        {
+            Ok(metadata) => metadata,
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        };
+    if metadata.subtitle_track(track).is_none() {
+        return error_response(StatusCode::NOT_FOUND, format!("No subtitle track {track}"));
+    }
+    let mut source = metadata.sidecar_path(track).map(Path::to_path_buf);
+    if metadata.unavailable_subs.contains(&track) {
+        // Bitmap tracks are read by OCR, and the text then converted like a
+        // sidecar file.
+        let Some(tesseract) = super::load_config().tesseract_path else {
+            return error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Subtitle track {track} is a bitmap format and OCR is not set up"),
+            );
+        };
+        let subtitle = metadata.subtitle_track(track).unwrap();
+        if !ocr::supports(&subtitle.codec) {
+            return error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("OCR cannot read {} subtitles", subtitle.codec),
+            );
+        }
+        match ocr::ocr_subtitle(
+            ffmpeg.clone(),
+            manager.clone(),
+            tesseract,
+            &params.path,
+            track,
+            subtitle.language.clone(),
+        ) {
+            Ok(OcrStatus::Done(file)) => source = Some(file),
+            Ok(OcrStatus::Running) => {
+                return Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .header(header::RETRY_AFTER, OCR_RETRY_SECS)
+                    .body(Body::from(format!("Reading subtitle track {track}")))
+                    .unwrap()
+            }
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        }
+    }
+    let offset = params.offset.unwrap_or(0.0);
+    if !offset.is_finite() {
+        return error_response(StatusCode::BAD_REQUEST, format!("Invalid offset {offset}"));
+    }
+    let format = params.format.unwrap_or_default();
+    // Only ASS itself survives as is: SSA needs its header rewritten.
+    let copy = format == SubtitleFormat::Ass
+        && metadata
+            .subtitle_track(track)
+            .is_some_and(|subtitle| subtitle.codec == "ass");
+    let job = match manager
+        .start_segment_job(&request_user(addr), &params.path, 0.0)
+        .await
+    {
+        Ok(job) => job,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    let data = match get_track(
+        &ffmpeg,
+        &job,
+        &params.path,
+        track,
+        source.as_deref(),
+        format,
+        copy,
+        offset,
+    )
+    .await
+    {
+        Ok(data) => data,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("inline; filename=\"{track}.{}\"", format.extension()),
+        )
+        .body(Body::from(data))
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+pub struct SubtitleEncodingRequest {
+    pub path: String,
+    /// Encoding label, e.g. `windows-1251`; `None` goes back to detection.
+    pub encoding: Option<String>,
+}
+
+/// `POST /subtitles/{track}/encoding`: sets the encoding a sidecar subtitle
+/// file is read in, for when detection gets it wrong. It is stored in the
+/// library database by subtitle file.
+pub async fn set_subtitle_encoding(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    UrlPath(track): UrlPath<u64>,
+    Json(request): Json<SubtitleEncodingRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&request.path) {
+        return response;
+    }
+    let probe = match probe_cache::probe(&ffmpeg, &request.path).await {
+        Ok(probe) => probe,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    // Sidecar ids follow the embedded subtitle tracks.
+    let embedded = probe
+        .info
+        .streams
+        .iter()
+        .filter(|stream| stream.codec_type == StreamType::Subtitle)
+        .count() as u64;
+    let Some(sidecar) = track
+        .checked_sub(embedded)
+        .and_then(|index| probe.sidecar_subtitles.get(index as usize))
+    else {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("No sidecar subtitle track {track}"),
+        );
+    };
+    let encoding = match request.encoding.as_deref().map(charset::encoding) {
+        Some(None) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown encoding {}", request.encoding.unwrap_or_default()),
+            )
+        }
+        Some(Some(encoding)) => Some(encoding.name().to_string()),
+        None => None,
+    };
+    if let Err(e) = api_servers::set_subtitle_encoding(&sidecar.path, encoding.clone()) {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+    }
+    println!("Subtitle encoding of {:?}: {encoding:?}", sidecar.path);
+    StatusCode::NO_CONTENT.into_response()
+}
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::{extract::Extension, response::IntoResponse, Json};
+use serde::Serialize;
+use tokio::{
+    process::{ChildStderr, ChildStdout, Command},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::processes::{spawn_tracked, TrackedProcess};
+use super::read_ahead::ReadAheadCache;
+use super::segment_cache::SegmentCache;
+use super::Config;
+
+/// The user background work, such as thumbnail generation, runs as.
+const BACKGROUND_USER: &str = "background";
+
+/// Sessions without a running job are forgotten after this long.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+struct SessionState {
+    user: String,
+    path: String,
+    position: f64,
+    started_at: SystemTime,
+    last_active: Instant,
+    jobs: HashMap<u64, JobEntry>,
+    pids: Vec<u32>,
+    read_ahead: ReadAheadCache,
+}
+
+struct JobEntry {
+    cancel: CancellationToken,
+    /// Speculative work for chunks the player has not asked for yet.
+    read_ahead: bool,
+}
+
+/// A playback session: everything one client transcodes for one file.
+struct Session {
+    id: String,
+    state: Mutex<SessionState>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user: String,
+    pub path: String,
+    pub position: f64,
+    pub started_at: u64,
+    pub idle_secs: u64,
+    pub active_jobs: usize,
+    pub pids: Vec<u32>,
+    pub read_ahead_chunks: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ManagerStatus {
+    pub max_transcodes: usize,
+    pub max_transcodes_per_user: usize,
+    pub available_slots: usize,
+    pub sessions: Vec<SessionInfo>,
+}
+
+/// Tracks playback sessions and caps how many transcodes run at once, both
+/// globally and per user. Work over the cap waits in a queue for a slot and
+/// is rejected if none frees up in time.
+pub struct TranscodeManager {
+    max_transcodes: usize,
+    max_transcodes_per_user: usize,
+    queue_timeout: Duration,
+    read_ahead_chunks: usize,
+    global: Arc<Semaphore>,
+    users: Mutex<HashMap<String, Arc<Semaphore>>>,
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    next_job_id: AtomicU64,
+    cache: Arc<SegmentCache>,
+}
+
+/// One unit of transcoding work, e.g. a `/video` chunk or an HLS segment.
+/// It holds a concurrency slot while alive, and dropping it kills every
+/// process it started.
+pub struct TranscodeJob {
+    id: u64,
+    session: Arc<Session>,
+    cancel: CancellationToken,
+    cache: Arc<SegmentCache>,
+    _permits: (OwnedSemaphorePermit, OwnedSemaphorePermit),
+}
+
+/// A process started by a job, listed under the job's session while running.
+pub struct JobProcess {
+    process: Option<TrackedProcess>,
+    pid: Option<u32>,
+    session: Arc<Session>,
+}
+
+impl TranscodeManager {
+    pub fn new(config: &Config, cache: Arc<SegmentCache>) -> Self {
+        TranscodeManager {
+            max_transcodes: config.max_transcodes,
+            max_transcodes_per_user: config.max_transcodes_per_user,
+            queue_timeout: Duration::from_secs(config.transcode_queue_timeout_secs),
+            read_ahead_chunks: config.read_ahead_chunks,
+            global: Arc::new(Semaphore::new(config.max_transcodes)),
+            users: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            next_job_id: AtomicU64::new(0),
+            cache,
+        }
+    }
+
+    fn session(&self, id: &str, user: &str, path: &str) -> Arc<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| {
+            let state = session.state.lock().unwrap();
+            !state.jobs.is_empty() || state.last_active.elapsed() < SESSION_IDLE_TIMEOUT
+        });
+        sessions
+            .entry(id.to_string())
+            .or_insert_with(|| {
+                Arc::new(Session {
+                    id: id.to_string(),
+                    state: Mutex::new(SessionState {
+                        user: user.to_string(),
+                        path: path.to_string(),
+                        position: 0.0,
+                        started_at: SystemTime::now(),
+                        last_active: Instant::now(),
+                        jobs: HashMap::new(),
+                        pids: Vec::new(),
+                        read_ahead: ReadAheadCache::default(),
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    /// Starts a job for `user` in the session `session_id`. With `supersede`
+    /// the session's earlier jobs are cancelled first, which is what a seek
+    /// in the player means. Waits for a free slot up to the queue timeout.
+    pub async fn start_job(
+        &self,
+        user: &str,
+        session_id: &str,
+        path: &str,
+        position: f64,
+        supersede: bool,
+    ) -> Result<TranscodeJob, String> {
+        let session = self.session(session_id, user, path);
+        {
+            let mut state = session.state.lock().unwrap();
+            if supersede {
+                for job in state.jobs.values().filter(|job| !job.read_ahead) {
+                    job.cancel.cancel();
+                }
+            }
+            state.path = path.to_string();
+            state.position = position;
+            state.last_active = Instant::now();
+        }
+
+        let user_slots = self.user_slots(user);
+        let acquire = async {
+            let user_permit = user_slots.acquire_owned().await;
+            let global_permit = self.global.clone().acquire_owned().await;
+            (user_permit, global_permit)
+        };
+        let permits = match tokio::time::timeout(self.queue_timeout, acquire).await {
+            Ok((Ok(user_permit), Ok(global_permit))) => (user_permit, global_permit),
+            _ => {
+                return Err(format!(
+                    "Too many transcodes running (limit {} in total, {} per user), try again later",
+                    self.max_transcodes, self.max_transcodes_per_user
+                ))
+            }
+        };
+
+        Ok(self.register_job(session, permits, false))
+    }
+
+    /// Starts a read-ahead job only if a slot is free right now, so
+    /// speculative work never delays what players are waiting for.
+    pub fn try_start_read_ahead_job(
+        &self,
+        user: &str,
+        session_id: &str,
+        path: &str,
+    ) -> Option<TranscodeJob> {
+        let session = self.session(session_id, user, path);
+        let user_permit = self.user_slots(user).try_acquire_owned().ok()?;
+        let global_permit = self.global.clone().try_acquire_owned().ok()?;
+        Some(self.register_job(session, (user_permit, global_permit), true))
+    }
+
+    /// Starts a job for work no client is waiting on, such as thumbnails.
+    /// All of it shares the slots of one user, so it never takes more than
+    /// a user's share from playback. It waits for a slot as long as it takes.
+    pub async fn start_background_job(&self, path: &str) -> TranscodeJob {
+        let session_id = format!("{BACKGROUND_USER}:{path}");
+        let session = self.session(&session_id, BACKGROUND_USER, path);
+        // The semaphores are never closed.
+        let user_permit = self
+            .user_slots(BACKGROUND_USER)
+            .acquire_owned()
+            .await
+            .unwrap();
+        let global_permit = self.global.clone().acquire_owned().await.unwrap();
+        self.register_job(session, (user_permit, global_permit), false)
+    }
+
+    pub fn read_ahead_chunks(&self) -> usize {
+        self.read_ahead_chunks
+    }
+
+    /// Records playback of a chunk that needed no new job.
+    pub fn update_position(&self, user: &str, session_id: &str, path: &str, position: f64) {
+        let session = self.session(session_id, user, path);
+        let mut state = session.state.lock().unwrap();
+        state.position = position;
+        state.last_active = Instant::now();
+    }
+
+    /// Runs `f` on the read-ahead cache of a session.
+    pub fn with_read_ahead<R>(
+        &self,
+        user: &str,
+        session_id: &str,
+        path: &str,
+        f: impl FnOnce(&mut ReadAheadCache) -> R,
+    ) -> R {
+        let session = self.session(session_id, user, path);
+        let mut state = session.state.lock().unwrap();
+        f(&mut state.read_ahead)
+    }
+
+    fn user_slots(&self, user: &str) -> Arc<Semaphore> {
+        let mut users = self.users.lock().unwrap();
+        // Permits and waiters hold the semaphore too, so a user only the map
+        // refers to has all its slots back.
+        users.retain(|_, slots| Arc::strong_count(slots) > 1);
+        users
+            .entry(user.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_transcodes_per_user)))
+            .clone()
+    }
+
+    fn register_job(
+        &self,
+        session: Arc<Session>,
+        permits: (OwnedSemaphorePermit, OwnedSemaphorePermit),
+        read_ahead: bool,
+    ) -> TranscodeJob {
+        let id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = CancellationToken::new();
+        session.state.lock().unwrap().jobs.insert(
+            id,
+            JobEntry {
+                cancel: cancel.clone(),
+                read_ahead,
+            },
+        );
+        TranscodeJob {
+            id,
+            session,
+            cancel,
+            cache: self.cache.clone(),
+            _permits: permits,
+        }
+    }
+
+    /// Starts a job for one HLS or DASH segment. Players fetch those in
+    /// parallel, so they share a session per user and file without
+    /// superseding each other.
+    pub async fn start_segment_job(
+        &self,
+        user: &str,
+        path: &str,
+        position: f64,
+    ) -> Result<TranscodeJob, String> {
+        let session_id = format!("{user}:{path}");
+        self.start_job(user, &session_id, path, position, false)
+            .await
+    }
+
+    pub fn status(&self) -> ManagerStatus {
+        let sessions = self.sessions.lock().unwrap();
+        let mut infos: Vec<SessionInfo> = sessions
+            .values()
+            .map(|session| {
+                let state = session.state.lock().unwrap();
+                SessionInfo {
+                    id: session.id.clone(),
+                    user: state.user.clone(),
+                    path: state.path.clone(),
+                    position: state.position,
+                    started_at: state
+                        .started_at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    idle_secs: state.last_active.elapsed().as_secs(),
+                    active_jobs: state.jobs.len(),
+                    pids: state.pids.clone(),
+                    read_ahead_chunks: state.read_ahead.chunk_count(),
+                }
+            })
+            .collect();
+        infos.sort_by(|a, b| a.id.cmp(&b.id));
+        ManagerStatus {
+            max_transcodes: self.max_transcodes,
+            max_transcodes_per_user: self.max_transcodes_per_user,
+            available_slots: self.global.available_permits(),
+            sessions: infos,
+        }
+    }
+}
+
+impl TranscodeJob {
+    pub fn cache(&self) -> Arc<SegmentCache> {
+        self.cache.clone()
+    }
+
+    /// Spawns an ffmpeg process that is killed when this job is cancelled or
+    /// dropped.
+    pub fn spawn(&self, command: Command) -> std::io::Result<JobProcess> {
+        let process = spawn_tracked(command, self.cancel.clone())?;
+        let pid = process.pid;
+        if let Some(pid) = pid {
+            self.session.state.lock().unwrap().pids.push(pid);
+        }
+        Ok(JobProcess {
+            process: Some(process),
+            pid,
+            session: self.session.clone(),
+        })
+    }
+}
+
+impl Drop for TranscodeJob {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        let mut state = self.session.state.lock().unwrap();
+        state.jobs.remove(&self.id);
+        state.last_active = Instant::now();
+    }
+}
+
+impl JobProcess {
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.process.as_mut()?.stdout.take()
+    }
+
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.process.as_mut()?.stderr.take()
+    }
+
+    /// Waits for the process to exit on its own, and returns whether it
+    /// succeeded.
+    pub async fn wait(mut self) -> bool {
+        match self.process.take() {
+            Some(process) => process.wait().await.is_some_and(|status| status.success()),
+            None => false,
+        }
+    }
+}
+
+impl Drop for JobProcess {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            self.session
+                .state
+                .lock()
+                .unwrap()
+                .pids
+                .retain(|p| *p != pid);
+        }
+    }
+}
+
+/// Identifies the user behind a request by the client address, which the
+/// client cannot choose, so it cannot get around its transcode limit.
+pub fn request_user(addr: SocketAddr) -> String {
+    addr.ip().to_string()
+}
+
+pub async fn get_sessions(
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+) -> impl IntoResponse {
+    Json(manager.status())
+}
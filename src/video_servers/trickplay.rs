@@ -0,0 +1,412 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use axum::{
+    body::Body,
+    extract::{Extension, Path as UrlPath, Query},
+    http::status::StatusCode,
+    response::{IntoResponse, Response},
+};
+use fnv::FnvHasher;
+use hyper::header;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use super::encoders::{ColorMode, EncoderBackend};
+use super::ffmpeg::FfmpegInfo;
+use super::hls::error_response;
+use super::probe::HdrFormat;
+use super::probe_cache;
+use super::transcode_manager::{TranscodeJob, TranscodeManager};
+
+/// Width of a thumbnail; the height follows the aspect ratio of the video.
+const THUMBNAIL_WIDTH: u32 = 320;
+/// Thumbnails per row and per column of a sprite sheet.
+const TILES: u32 = 10;
+/// Seconds a client should wait before asking again for thumbnails being
+/// generated.
+const RETRY_SECS: u64 = 30;
+
+/// The sprite sheets of a file, described by `index.json` next to them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sheets {
+    /// Seconds of video each thumbnail stands for.
+    pub interval: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Thumbnails over all sheets; the last sheet may be partly empty.
+    pub count: u64,
+}
+
+impl Sheets {
+    /// WebVTT track with a cue per thumbnail, pointing into the sheets served
+    /// next to it with a media fragment, as video.js and other players expect.
+    fn vtt(&self, duration: f64, query: &str) -> String {
+        let per_sheet = (TILES * TILES) as u64;
+        let mut vtt = String::from("WEBVTT\n\n");
+        for index in 0..self.count {
+            let start = (index * self.interval) as f64;
+            let end = (((index + 1) * self.interval) as f64).min(duration);
+            if start >= end {
+                break;
+            }
+            let tile = (index % per_sheet) as u32;
+            let x = tile % TILES * self.width;
+            let y = tile / TILES * self.height;
+            let _ = write!(
+                vtt,
+                "{} --> {}\nsheets/{}?{query}#xywh={x},{y},{},{}\n\n",
+                vtt_timestamp(start),
+                vtt_timestamp(end),
+                index / per_sheet,
+                self.width,
+                self.height,
+            );
+        }
+        vtt
+    }
+}
+
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Where the sheets of this version of the file go.
+fn sheets_dir(path: &str, interval: u64) -> Option<PathBuf> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let mut hasher = FnvHasher::default();
+    path.hash(&mut hasher);
+    mtime.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    interval.hash(&mut hasher);
+    THUMBNAIL_WIDTH.hash(&mut hasher);
+    Some(
+        super::cache_dir()
+            .join("trickplay")
+            .join(format!("{:016x}", hasher.finish())),
+    )
+}
+
+fn load(dir: &Path) -> Option<Sheets> {
+    let data = fs::read(dir.join("index.json")).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Extracts a frame every `interval` seconds and tiles them into numbered
+/// JPEG sheets in `dir`. Only keyframes are decoded, which is many times
+/// faster, so a thumbnail may be from a few seconds off.
+async fn generate(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    path: &str,
+    interval: u64,
+    dir: &Path,
+) -> Result<(), String> {
+    let probe = probe_cache::probe(ffmpeg, path).await?;
+    let video = probe
+        .info
+        .video_stream()
+        .ok_or_else(|| format!("No video stream in {path}"))?;
+    let (Some(width), Some(height)) = (video.width, video.height) else {
+        return Err(format!("Unknown video size of {path}"));
+    };
+    // Even, as the chroma of JPEG is subsampled.
+    let thumbnail_height =
+        (((THUMBNAIL_WIDTH * height) as f64 / width as f64 / 2.0).round() as u32 * 2).max(2);
+    let color = match video.hdr {
+        Some(HdrFormat::DolbyVision) => {
+            return Err("Dolby Vision profile 5 video cannot be tone mapped".to_string())
+        }
+        Some(format) => ColorMode::Tonemap(format),
+        None => ColorMode::Sdr,
+    };
+    let filter = format!(
+        "fps=1/{interval},{},tile={TILES}x{TILES}",
+        EncoderBackend::Libx264.video_filter(THUMBNAIL_WIDTH, thumbnail_height, color)
+    );
+
+    // Written next to the final directory and renamed, so an interrupted
+    // run is never mistaken for a complete one.
+    let partial = dir.with_extension("partial");
+    let _ = fs::remove_dir_all(&partial);
+    fs::create_dir_all(&partial).map_err(|e| format!("Failed to create {partial:?}: {e}"))?;
+    let mut command = Command::new(&ffmpeg.ffmpeg_path);
+    command
+        .args(["-v", "error", "-y"])
+        .args(["-skip_frame", "nokey"])
+        .args(["-i", path])
+        .args(["-map", &format!("0:{}", video.index)])
+        .args(["-vf", &filter])
+        .args(["-q:v", "5"])
+        .args(["-start_number", "0"])
+        .arg(partial.join("%d.jpg"));
+    let success = match job.spawn(command) {
+        Ok(process) => process.wait().await,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&partial);
+            return Err(format!("Failed to start FFmpeg: {e}"));
+        }
+    };
+    let sheets = (0..)
+        .take_while(|index| partial.join(format!("{index}.jpg")).exists())
+        .count() as u64;
+    if !success || sheets == 0 {
+        let _ = fs::remove_dir_all(&partial);
+        return Err("FFmpeg failed to extract thumbnails".to_string());
+    }
+    let per_sheet = (TILES * TILES) as u64;
+    let index = Sheets {
+        interval,
+        width: THUMBNAIL_WIDTH,
+        height: thumbnail_height,
+        count: ((probe.duration / interval as f64).ceil() as u64).min(sheets * per_sheet),
+    };
+    fs::write(
+        partial.join("index.json"),
+        serde_json::to_vec(&index).unwrap(),
+    )
+    .and_then(|_| fs::rename(&partial, dir))
+    .map_err(|e| format!("Failed to save thumbnails: {e}"))?;
+    println!("Generated {} thumbnails of {path}", index.count);
+    Ok(())
+}
+
+/// A failed file is not tried again for this long.
+const FAILURE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// When a job failed, and why.
+type Failure = (Instant, String);
+
+/// Generation in progress (`None`) or failed (`Some`) per directory.
+static JOBS: LazyLock<Mutex<HashMap<PathBuf, Option<Failure>>>> = LazyLock::new(Default::default);
+
+static WARMING: AtomicBool = AtomicBool::new(false);
+
+/// Claims the job of generating `dir`, unless it is running or has failed
+/// recently.
+fn claim(dir: &Path) -> Result<bool, String> {
+    match JOBS.lock().unwrap().entry(dir.to_path_buf()) {
+        Entry::Occupied(mut job) => match job.get() {
+            Some((failed, e)) if failed.elapsed() < FAILURE_TIMEOUT => Err(e.clone()),
+            Some(_) => {
+                job.insert(None);
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        Entry::Vacant(job) => {
+            job.insert(None);
+            Ok(true)
+        }
+    }
+}
+
+/// Runs a claimed job once a background transcode slot is free, and records
+/// how it went.
+async fn run(
+    ffmpeg: &FfmpegInfo,
+    manager: &TranscodeManager,
+    path: &str,
+    interval: u64,
+    dir: PathBuf,
+) {
+    let job = manager.start_background_job(path).await;
+    let generated = generate(ffmpeg, &job, path, interval, &dir).await;
+    let mut jobs = JOBS.lock().unwrap();
+    match generated {
+        Ok(()) => {
+            jobs.remove(&dir);
+        }
+        Err(e) => {
+            eprintln!("Thumbnails of {path}: {e}");
+            jobs.insert(dir, Some((Instant::now(), e)));
+        }
+    }
+}
+
+pub enum TrickplayStatus {
+    Done(PathBuf, Sheets),
+    /// Being generated in the background; ask again later.
+    Running,
+}
+
+/// The sheets of a file with a thumbnail every `interval` seconds (0 when
+/// they are turned off), starting to generate them in the background if
+/// they have not been made yet. They are kept in the cache directory until
+/// the file changes.
+pub fn sheets(
+    ffmpeg: Arc<FfmpegInfo>,
+    manager: Arc<TranscodeManager>,
+    path: &str,
+    interval: u64,
+) -> Result<TrickplayStatus, String> {
+    if interval == 0 {
+        return Err("Thumbnails are turned off".to_string());
+    }
+    let dir = sheets_dir(path, interval).ok_or_else(|| format!("File not found: {path}"))?;
+    if let Some(sheets) = load(&dir) {
+        return Ok(TrickplayStatus::Done(dir, sheets));
+    }
+    if claim(&dir)? {
+        let path = path.to_string();
+        tokio::spawn(async move { run(&ffmpeg, &manager, &path, interval, dir).await });
+    }
+    Ok(TrickplayStatus::Running)
+}
+
+/// Generates the thumbnails of the given files in the background, one at a
+/// time. Does nothing if this is already running.
+pub fn warm(
+    ffmpeg: Arc<FfmpegInfo>,
+    manager: Arc<TranscodeManager>,
+    paths: Vec<String>,
+    interval: u64,
+) {
+    if interval == 0 || WARMING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    tokio::spawn(async move {
+        for path in paths {
+            let Some(dir) = sheets_dir(&path, interval) else {
+                continue;
+            };
+            if dir.exists() || !matches!(claim(&dir), Ok(true)) {
+                continue;
+            }
+            run(&ffmpeg, &manager, &path, interval, dir).await;
+        }
+        WARMING.store(false, Ordering::Release);
+    });
+}
+
+#[derive(Deserialize)]
+pub struct TrickplayRequest {
+    pub path: String,
+}
+
+/// Tells the client the thumbnails are not ready yet.
+fn generating() -> Response {
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header(header::RETRY_AFTER, RETRY_SECS)
+        .body(Body::from("Generating thumbnails"))
+        .unwrap()
+}
+
+/// `GET /trickplay/thumbnails.vtt`: WebVTT thumbnail track for seek bar
+/// previews, each cue pointing at a tile of a sprite sheet.
+pub async fn serve_thumbnails(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    Query(params): Query<TrickplayRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let interval = super::load_config().trickplay_interval_secs;
+    let sheets = match sheets(ffmpeg.clone(), manager, &params.path, interval) {
+        Ok(TrickplayStatus::Done(_, sheets)) => sheets,
+        Ok(TrickplayStatus::Running) => return generating(),
+        Err(e) => return error_response(StatusCode::NOT_FOUND, e),
+    };
+    let duration = match probe_cache::probe(&ffmpeg, &params.path).await {
+        Ok(probe) => probe.duration,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    let query = serde_urlencoded::to_string([("path", &params.path)]).unwrap();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/vtt; charset=utf-8")
+        .body(Body::from(sheets.vtt(duration, &query)))
+        .unwrap()
+}
+
+/// `GET /trickplay/sheets/{sheet}`: a JPEG sprite sheet of thumbnails, as
+/// referenced by `/trickplay/thumbnails.vtt`.
+pub async fn serve_sheet(
+    Extension(ffmpeg): Extension<Arc<FfmpegInfo>>,
+    Extension(manager): Extension<Arc<TranscodeManager>>,
+    UrlPath(sheet): UrlPath<u64>,
+    Query(params): Query<TrickplayRequest>,
+) -> impl IntoResponse {
+    if let Some(response) = super::forbid_outside_library(&params.path) {
+        return response;
+    }
+    let interval = super::load_config().trickplay_interval_secs;
+    let dir = match sheets(ffmpeg, manager, &params.path, interval) {
+        Ok(TrickplayStatus::Done(dir, _)) => dir,
+        Ok(TrickplayStatus::Running) => return generating(),
+        Err(e) => return error_response(StatusCode::NOT_FOUND, e),
+    };
+    match fs::read(dir.join(format!("{sheet}.jpg"))) {
+        Ok(data) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "image/jpeg")
+            .body(Body::from(data))
+            .unwrap(),
+        Err(_) => error_response(StatusCode::NOT_FOUND, format!("No sheet {sheet}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vtt_cues_continue_on_the_next_sheet() {
+        let sheets = Sheets {
+            interval: 10,
+            width: 320,
+            height: 180,
+            count: 150,
+        };
+        let vtt = sheets.vtt(1495.0, "path=movie.mkv");
+        let cues: Vec<&str> = vtt
+            .strip_prefix("WEBVTT\n\n")
+            .unwrap()
+            .split_terminator("\n\n")
+            .collect();
+        assert_eq!(cues.len(), 150);
+        assert_eq!(
+            cues[0],
+            "00:00:00.000 --> 00:00:10.000\nsheets/0?path=movie.mkv#xywh=0,0,320,180"
+        );
+        assert_eq!(
+            cues[99],
+            "00:16:30.000 --> 00:16:40.000\nsheets/0?path=movie.mkv#xywh=2880,1620,320,180"
+        );
+        assert_eq!(
+            cues[100],
+            "00:16:40.000 --> 00:16:50.000\nsheets/1?path=movie.mkv#xywh=0,0,320,180"
+        );
+        assert_eq!(
+            cues[111],
+            "00:18:30.000 --> 00:18:40.000\nsheets/1?path=movie.mkv#xywh=320,180,320,180"
+        );
+        // The last cue ends with the video.
+        assert_eq!(
+            cues[149],
+            "00:24:50.000 --> 00:24:55.000\nsheets/1?path=movie.mkv#xywh=2880,720,320,180"
+        );
+    }
+}
@@ -1,12 +1,29 @@
+use axum::body::Bytes;
+use futures::{future, stream, stream::BoxStream, StreamExt};
 use serde::Serialize;
-use serde_json::Value;
-use std::{ffi::OsStr, process::Stdio, sync::Arc};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    process::Command,
-    sync::Mutex,
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use super::attachments::{self, Font};
+use super::charset;
+use super::device_profile::DeviceProfile;
+use super::encoders::{AudioCodec, ColorMode, Normalization, VideoCodec};
+use super::ffmpeg::FfmpegInfo;
+use super::framing::{Framing, TrackHeader, TrackKind};
+use super::playback::{self, AudioSource, PlaybackDecision, PlaybackMode, SourceInfo};
+use super::probe::{HdrFormat, MediaInfo, StreamType};
+use super::probe_cache;
+use super::quality::{self, Rendition};
+use super::segment_cache::SegmentKey;
+use super::transcode_manager::TranscodeJob;
+use tokio::{io::AsyncReadExt, process::Command, sync::oneshot};
+use tokio_util::io::ReaderStream;
+
+use crate::api_servers;
+
 #[derive(Serialize, Debug, PartialEq, Eq)]
 pub enum Tracktype {
     Audio,
@@ -19,6 +36,15 @@ pub struct Track {
     pub id: u64,
     pub kind: Tracktype,
     pub label: String,
+    pub codec: String,
+    pub language: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+    pub hearing_impaired: bool,
+    /// Audio tracks only.
+    pub channels: Option<u32>,
+    /// Sidecar subtitles only: the encoding the file is read in.
+    pub encoding: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -26,450 +52,1155 @@ pub struct VideoMetadata {
     pub duration: f64,
     pub tracks: Vec<Track>,
     pub unavailable_subs: Vec<u64>,
+    pub container: String,
+    pub playback: PlaybackDecision,
+    /// Codec strings of the streams sent by `/video`, for MSE `addSourceBuffer`.
+    pub video_codec: String,
+    pub audio_codec: String,
+    /// Everything ffprobe reported about the source: format, streams and chapters.
+    pub source: Arc<MediaInfo>,
+    /// Quality profiles offered for this source, smallest first.
+    pub qualities: Vec<Rendition>,
+    /// The client profile the playback decision was made for.
+    pub device_profile: DeviceProfile,
+    /// Fonts attached to the file, for rendering its ASS subtitles.
+    pub fonts: Vec<Font>,
+    /// Track ids and files of the sidecar subtitles.
+    #[serde(skip)]
+    pub sidecars: Vec<(u64, PathBuf)>,
 }
 
-pub async fn get_video_metadata(input_path: &str) -> Result<VideoMetadata, String> {
+/// How to treat the source colors when encoding to `target`. HDR is only
+/// kept for an HDR display and HEVC output, the only 10-bit codec we encode.
+fn color_mode(source: &MediaInfo, profile: &DeviceProfile, target: VideoCodec) -> ColorMode {
+    match source.video_stream().and_then(|video| video.hdr) {
+        None => ColorMode::Sdr,
+        Some(format) if profile.hdr && target == VideoCodec::Hevc => ColorMode::Hdr(format),
+        Some(format) => ColorMode::Tonemap(format),
+    }
+}
+
+impl VideoMetadata {
+    fn source_size(&self) -> Option<(u32, u32)> {
+        let video = self.source.video_stream()?;
+        Some((video.width?, video.height?))
+    }
+
+    /// The rendition of a requested quality profile, or of the default one.
+    pub fn rendition(&self, quality: Option<&str>) -> Result<Rendition, String> {
+        let name = quality.unwrap_or(quality::DEFAULT_QUALITY);
+        let profile = quality::find(name).ok_or_else(|| format!("Unknown quality {name}"))?;
+        Ok(profile
+            .within(self.device_profile.max_size())
+            .rendition(self.source_size()))
+    }
+
+    /// How re-encoded video treats the source colors.
+    pub fn color_mode(&self) -> ColorMode {
+        color_mode(
+            &self.source,
+            &self.device_profile,
+            self.playback.video_target,
+        )
+    }
+
+    /// Settings for re-encoding the video at a quality profile.
+    pub fn transcode_options(&self, quality: Option<&str>) -> Result<VideoOptions, String> {
+        Ok(VideoOptions {
+            stream_copy: false,
+            codec: self.playback.video_target,
+            rendition: self.rendition(quality)?,
+            color: self.color_mode(),
+            burn_in: None,
+        })
+    }
+
+    /// Refuses video that cannot be re-encoded: Dolby Vision profile 5 has
+    /// no base layer to tone map or keep as HDR10 without its metadata.
+    pub fn check_reencode(&self) -> Result<(), String> {
+        match self.source.video_stream().and_then(|video| video.hdr) {
+            Some(HdrFormat::DolbyVision) => Err(
+                "Dolby Vision profile 5 video cannot be transcoded or tone mapped; play it directly on a Dolby Vision display"
+                    .to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Settings for video cut into HLS or DASH segments, which is always
+    /// re-encoded.
+    pub fn segment_video_options(&self, quality: Option<&str>) -> Result<VideoOptions, String> {
+        self.check_reencode()?;
+        self.transcode_options(quality)
+    }
+
+    pub fn audio_track(&self, id: u64) -> Option<&Track> {
+        self.tracks
+            .iter()
+            .find(|track| track.id == id && track.kind == Tracktype::Audio)
+    }
+
+    pub fn subtitle_track(&self, id: u64) -> Option<&Track> {
+        self.tracks
+            .iter()
+            .find(|track| track.id == id && matches!(track.kind, Tracktype::Subtitle(_)))
+    }
+
+    /// The file of a sidecar subtitle track, or `None` for an embedded one.
+    pub fn sidecar_path(&self, id: u64) -> Option<&Path> {
+        self.sidecars
+            .iter()
+            .find(|(sidecar_id, _)| *sidecar_id == id)
+            .map(|(_, path)| path.as_path())
+    }
+
+    /// Settings for the audio tracks.
+    pub fn audio_options(&self) -> AudioOptions {
+        AudioOptions {
+            stream_copy: self.playback.copy_audio,
+            codec: self.playback.audio_target,
+            max_channels: self.device_profile.audio_channels,
+            normalization: self.device_profile.normalization,
+        }
+    }
+
+    /// Settings for audio cut into HLS or DASH segments, which is always
+    /// re-encoded so every segment starts exactly on its boundary.
+    pub fn segment_audio_options(&self) -> AudioOptions {
+        AudioOptions {
+            stream_copy: false,
+            ..self.audio_options()
+        }
+    }
+
+    /// Whether the source video already fits a rendition, so copying it
+    /// gives the requested quality.
+    fn source_fits(&self, rendition: &Rendition) -> bool {
+        let bit_rate = self
+            .source
+            .video_stream()
+            .and_then(|video| video.bit_rate)
+            .or(self.source.format.bit_rate);
+        !rendition.scaled
+            && bit_rate.is_none_or(|bit_rate| bit_rate <= rendition.max_bitrate as u64 * 1000)
+    }
+}
+
+/// Probes a file and decides how to play it on a client with `profile`.
+pub async fn get_video_metadata(
+    ffmpeg: &FfmpegInfo,
+    input_path: &str,
+    profile: &DeviceProfile,
+) -> Result<VideoMetadata, String> {
     println!("Input path: {input_path}");
-    let output = Command::new("ffprobe")
-        .args(["-v", "quiet"])
-        .args(["-print_format", "json"])
-        .args(["-show_streams"])
-        .args([input_path])
-        .output()
-        .await
-        .map_err(|_| "Failed to execute ffprobe")
-        .unwrap();
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let metadata: Value = serde_json::from_str(&stdout).unwrap();
+    let probe = probe_cache::probe(ffmpeg, input_path).await?;
+    let info = &probe.info;
     let mut tracks: Vec<Track> = Vec::new();
+    let mut source = SourceInfo {
+        format_name: info.format.format_name.clone(),
+        ..Default::default()
+    };
+    if let Some(video) = info.video_stream() {
+        source.video_codec = Some(video.codec_name.clone());
+        source.video_profile = video.profile.clone();
+        source.video_level = video.level;
+        source.pixel_format = video.pix_fmt.clone();
+        source.width = video.width;
+        source.height = video.height;
+        source.hdr = video.hdr.is_some();
+        source.dolby_vision = video
+            .dolby_vision()
+            .and_then(|dovi| Some((dovi.dv_profile?, dovi.dv_level?)));
+    }
 
-    println!("Metadata: {}", metadata["streams"]);
-    let metadata = metadata["streams"].as_array().unwrap();
     let mut audio_idx = -1;
     let mut subtitle_idx = -1;
 
     let mut unavailable_subs = Vec::new();
-    for stream in metadata {
-        if let Some(track_type) = stream.get("codec_type") {
-            let track_type = match track_type.as_str().unwrap() {
-                "audio" => Tracktype::Audio,
-                "video" => Tracktype::Video,
-                "subtitle" => Tracktype::Subtitle(false),
-                _ => continue,
-            };
-            let track_id = match track_type {
-                Tracktype::Audio => {
-                    audio_idx += 1;
-                    audio_idx
-                }
-                Tracktype::Video => 0,
-                Tracktype::Subtitle(_) => {
-                    subtitle_idx += 1;
-                    subtitle_idx
-                }
-            } as u64;
-            let tags = stream["tags"].as_object();
-            let label = if let Some(tags) = tags {
-                if let Some(label) = tags.get("title") {
-                    label.as_str().unwrap().to_string()
-                } else if let Some(label) = tags.get("language") {
-                    label.as_str().unwrap().to_string()
-                } else {
-                    match track_type {
-                        Tracktype::Audio => format!("Audio {track_id}"),
-                        Tracktype::Video => format!("Video {track_id}"),
-                        Tracktype::Subtitle(_) => format!("Subtitle {track_id}"),
-                    }
-                }
-            } else {
-                format!("Track {track_id}")
-            };
-            if track_type == Tracktype::Subtitle(false) {
-                let sub_codec = stream["codec_name"].as_str().unwrap();
-                let graphic_codecs = vec!["dvbsub", "dvdsub", "pgs", "xsub"];
-                for graphic_codec in graphic_codecs {
-                    if sub_codec.contains(graphic_codec) {
-                        unavailable_subs.push(track_id);
-                    }
+    let mut fonts = Vec::new();
+    let mut attachment_idx = 0;
+    for stream in &info.streams {
+        if stream.codec_type == StreamType::Attachment {
+            fonts.extend(attachments::font(stream, attachment_idx));
+            attachment_idx += 1;
+        }
+        let track_type = match stream.codec_type {
+            StreamType::Audio => Tracktype::Audio,
+            StreamType::Video if !stream.disposition.attached_pic => Tracktype::Video,
+            StreamType::Subtitle => Tracktype::Subtitle(false),
+            _ => continue,
+        };
+        let track_id = match track_type {
+            Tracktype::Audio => {
+                audio_idx += 1;
+                audio_idx
+            }
+            Tracktype::Video => 0,
+            Tracktype::Subtitle(_) => {
+                subtitle_idx += 1;
+                subtitle_idx
+            }
+        } as u64;
+        let label = match (stream.tags.title(), stream.tags.language()) {
+            (Some(title), _) => title.to_string(),
+            (None, Some(language)) => language.to_string(),
+            (None, None) => match track_type {
+                Tracktype::Audio => format!("Audio {track_id}"),
+                Tracktype::Video => format!("Video {track_id}"),
+                Tracktype::Subtitle(_) => format!("Subtitle {track_id}"),
+            },
+        };
+        let codec = stream.codec_name.clone();
+        if track_type == Tracktype::Audio {
+            source.audio.push(AudioSource {
+                codec: codec.clone(),
+                profile: stream.profile.clone(),
+                channels: stream.channels,
+            });
+        }
+        if track_type == Tracktype::Subtitle(false) {
+            source.subtitle_codecs.push(codec.clone());
+            let sub_codec = codec.as_str();
+            let graphic_codecs = vec![
+                "dvbsub",
+                "dvdsub",
+                "dvb_subtitle",
+                "dvd_subtitle",
+                "pgs",
+                "xsub",
+            ];
+            for graphic_codec in graphic_codecs {
+                if sub_codec.contains(graphic_codec) {
+                    unavailable_subs.push(track_id);
                 }
             }
-            let track = Track {
-                id: track_id,
-                kind: track_type,
-                label,
-            };
-            tracks.push(track);
         }
+        let track = Track {
+            id: track_id,
+            kind: track_type,
+            label,
+            codec,
+            language: stream.tags.language().map(str::to_string),
+            default: stream.disposition.default,
+            forced: stream.disposition.forced,
+            hearing_impaired: stream.disposition.hearing_impaired,
+            channels: stream
+                .channels
+                .filter(|_| stream.codec_type == StreamType::Audio),
+            encoding: None,
+        };
+        tracks.push(track);
     }
 
-    // Check if there exists a subtitle file right beside the video
-    let video_path = std::path::Path::new(input_path);
-    let video_dir = video_path.parent().unwrap();
-    let subtitle_exts = [OsStr::new("srt"), OsStr::new("vtt")];
-
-    for file in video_dir.read_dir().unwrap() {
-        let subtitle_path = file.unwrap().path();
-        if let Some(ext) = subtitle_path.extension() {
-            if !subtitle_exts.contains(&ext) {
-                continue;
-            }
-        } else {
-            continue;
+    let mut sidecars = Vec::new();
+    for sidecar in &probe.sidecar_subtitles {
+        println!("Subtitle path: {}", sidecar.path.display());
+        subtitle_idx += 1;
+        let mut label = match &sidecar.language {
+            Some(language) => language.clone(),
+            None => sidecar
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        };
+        if sidecar.forced {
+            label.push_str(" (Forced)");
         }
-        println!("Subtitle path: {}", subtitle_path.display());
-        if subtitle_path.exists() {
-            subtitle_idx += 1;
-            let track = Track {
-                id: subtitle_idx as u64,
-                kind: Tracktype::Subtitle(true),
-                label: subtitle_path
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string(),
-            };
-            tracks.push(track);
+        if sidecar.hearing_impaired {
+            label.push_str(" (SDH)");
         }
+        // ffmpeg reads text subtitles as UTF-8, so others are converted
+        // first. An encoding set in the library wins over the detected one.
+        let encoding = api_servers::subtitle_encoding(&sidecar.path)
+            .or_else(|| sidecar.encoding.clone())
+            .and_then(|label| charset::encoding(&label))
+            .unwrap_or(encoding_rs::UTF_8);
+        let file = charset::utf8_file(&sidecar.path, encoding).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            sidecar.path.clone()
+        });
+        let track = Track {
+            id: subtitle_idx as u64,
+            kind: Tracktype::Subtitle(true),
+            label,
+            codec: sidecar.format.clone(),
+            language: sidecar.language.clone(),
+            default: false,
+            forced: sidecar.forced,
+            hearing_impaired: sidecar.hearing_impaired,
+            channels: None,
+            encoding: Some(encoding.name().to_string()),
+        };
+        sidecars.push((track.id, file));
+        tracks.push(track);
     }
+    let duration = probe.duration;
 
-    let output = Command::new("ffprobe")
-        .args(["-select_streams", "v:0"])
-        .args(["-show_entries", "format=duration"])
-        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
-        .args([input_path])
-        .output()
-        .await
-        .map_err(|_| "Failed to execute ffprobe")
-        .unwrap();
-
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let mut lines = output_str.lines();
-    let duration = lines
-        .next()
-        .and_then(|s| s.trim().parse::<f64>().ok())
-        .unwrap();
+    let playback = playback::decide(&source, profile, ffmpeg.encoder, &ffmpeg.encoders);
+    let video_codec = match source.compatible_video_codec(profile) {
+        Ok(codec) if playback.mode != PlaybackMode::Transcode => codec,
+        _ => playback
+            .video_target
+            .codec_string(color_mode(info, profile, playback.video_target))
+            .to_string(),
+    };
+    let audio_codec = source
+        .compatible_audio_codec(profile)
+        .unwrap_or(playback.audio_target.codec_string());
+    println!("Playback: {:?} ({})", playback.mode, playback.reason);
 
     let metadata = VideoMetadata {
         tracks,
         duration,
         unavailable_subs,
+        container: source.format_name,
+        playback,
+        video_codec,
+        audio_codec: audio_codec.to_string(),
+        source: info.clone(),
+        qualities: quality::ladder(
+            info.video_stream()
+                .and_then(|video| Some((video.width?, video.height?))),
+            profile.max_size(),
+        ),
+        device_profile: profile.clone(),
+        fonts,
+        sidecars,
     };
     Ok(metadata)
 }
 
-#[derive(Default, Debug)]
-pub struct AudioData {
-    pub id: u64,
-    pub data: Vec<u8>,
+/// Container the transcoded chunks are muxed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// Fragmented MP4 for MSE, one self-contained fragment per chunk.
+    FragmentedMp4,
+    /// MPEG-TS with timestamps offset to the chunk start, for HLS segments.
+    MpegTs,
+    /// Fragmented MP4 whose fragments carry absolute decode times, for DASH
+    /// segments that are appended without a timestamp offset.
+    DashSegment,
 }
 
-#[derive(Serialize, Debug)]
-pub struct SubtitleData {
-    pub id: u64,
-    pub data: String,
+impl Container {
+    fn muxer_args(&self, start_timestamp: f64) -> Vec<String> {
+        match self {
+            Container::FragmentedMp4 => vec![
+                "-movflags".to_string(),
+                "frag_keyframe+empty_moov+faststart+default_base_moof".to_string(),
+                "-f".to_string(),
+                "mp4".to_string(),
+            ],
+            Container::MpegTs => vec![
+                "-output_ts_offset".to_string(),
+                start_timestamp.to_string(),
+                "-muxdelay".to_string(),
+                "0".to_string(),
+                "-muxpreload".to_string(),
+                "0".to_string(),
+                "-f".to_string(),
+                "mpegts".to_string(),
+            ],
+            Container::DashSegment => vec![
+                "-output_ts_offset".to_string(),
+                start_timestamp.to_string(),
+                "-movflags".to_string(),
+                "frag_keyframe+empty_moov+default_base_moof+frag_discont".to_string(),
+                "-f".to_string(),
+                "mp4".to_string(),
+            ],
+        }
+    }
 }
 
-#[derive(Default, Debug)]
-pub struct VideoResponse {
-    pub video_data: Vec<u8>,
-    pub audio_data: Vec<AudioData>,
-    pub subtitle_data: Vec<SubtitleData>,
+/// Per-request settings for the video stream.
+#[derive(Debug, Clone)]
+pub struct VideoOptions {
+    /// Copy the source stream instead of re-encoding it.
+    pub stream_copy: bool,
+    pub codec: VideoCodec,
+    /// Output size and bitrate cap when re-encoding.
+    pub rendition: Rendition,
+    pub color: ColorMode,
+    /// Subtitles drawn into the video while it is re-encoded.
+    pub burn_in: Option<BurnIn>,
 }
 
-// NOTE: The binary data is serialized as
-// [
-//     u32 -> number of audio tracks,
-//     u32 -> number of subtitle tracks,
-//     u64 -> data length of the video track,
-//     Vec<u8> -> video track data,
-//     -- For each audio track --
-//     u64 -> audio track id,
-//     u64 -> data length of the audio track,
-//     Vec<u8> -> audio track data,
-//     --
-// ]
-impl VideoResponse {
-    pub async fn as_bytes(&self) -> Vec<u8> {
-        let mut data = Vec::new();
-        data.write_u32_le(self.audio_data.len() as u32)
-            .await
-            .unwrap();
-        data.write_u32_le(self.subtitle_data.len() as u32)
-            .await
-            .unwrap();
-        data.write_u64_le(self.video_data.len() as u64)
-            .await
-            .unwrap();
-        data.write_all(&self.video_data).await.unwrap();
-        for audio in &self.audio_data {
-            data.write_u64_le(audio.id).await.unwrap();
-            data.write_u64_le(audio.data.len() as u64).await.unwrap();
-            data.write_all(&audio.data).await.unwrap();
+/// A subtitle track burned into the video.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BurnIn {
+    /// A bitmap subtitle track of the input, by subtitle track id.
+    Bitmap(u64),
+    /// A text subtitle track rendered with libass, keeping ASS styling.
+    Text {
+        /// The video for an embedded track, or the sidecar file.
+        file: PathBuf,
+        /// Subtitle track id within `file`, for embedded tracks.
+        stream: Option<u64>,
+        /// Fonts attached to the video, for sidecar files that use them.
+        fonts_dir: Option<PathBuf>,
+    },
+}
+
+/// Escapes a path for a filter option, once for the option parser and once
+/// for the filter graph parser.
+fn filter_path(path: &Path) -> String {
+    let mut option = String::new();
+    for c in path.to_string_lossy().chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            option.push('\\');
         }
-        for subtitle in &self.subtitle_data {
-            data.write_u64_le(subtitle.id).await.unwrap();
-            data.write_u64_le(subtitle.data.len() as u64).await.unwrap();
-            data.write_all(subtitle.data.as_bytes()).await.unwrap();
+        option.push(c);
+    }
+    let mut graph = String::new();
+    for c in option.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph.push('\\');
         }
-        data
+        graph.push(c);
     }
+    graph
 }
 
-pub async fn get_video_data(
-    path: &str,
-    start_timestamp: f64,
-    duration: Option<f64>,
-) -> Result<VideoResponse, String> {
-    let video_metadata = get_video_metadata(path).await?;
-    let mut video_data = VideoResponse::default();
-    let duration = duration.unwrap_or(10.0);
-    println!("Duration: {duration}");
-    for track in &video_metadata.tracks {
-        match track.kind {
-            Tracktype::Video => {
-                let video_stream = get_video(path, start_timestamp, duration).await;
-                video_data.video_data = video_stream;
-                println!("Video data: {}", video_data.video_data.len());
-            }
-            Tracktype::Audio => {
-                let audio_stream = get_audio(path, track.id, start_timestamp, duration).await;
-                println!("Audio data: {}", audio_stream.data.len());
-                video_data.audio_data.push(audio_stream);
+/// What a client asked for besides the time range.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Quality profile name; the default profile when `None`.
+    pub quality: Option<String>,
+    /// What the client can play.
+    pub profile: DeviceProfile,
+    pub framing: Framing,
+    /// Subtitle track to burn into the video, which is then re-encoded.
+    pub burn_subtitle: Option<u64>,
+}
+
+impl VideoOptions {
+    /// Arguments placed before `-i`: hardware decoding when re-encoding,
+    /// unless subtitles are drawn on the frames, which needs them on the CPU.
+    fn decode_args(&self, ffmpeg: &FfmpegInfo) -> Vec<String> {
+        match (self.stream_copy, &self.burn_in) {
+            (true, _) => Vec::new(),
+            (false, None) => ffmpeg.encoder.decode_args(),
+            (false, Some(_)) => ffmpeg.encoder.software_decode_args(),
+        }
+    }
+
+    /// Selects the video stream, through a filter graph drawing the burned
+    /// in subtitles if there are any.
+    fn map_args(&self, ffmpeg: &FfmpegInfo, start_timestamp: f64) -> Vec<String> {
+        let Some(burn_in) = &self.burn_in else {
+            return vec!["-map".to_string(), "0:v:0".to_string()];
+        };
+        let rendition = &self.rendition;
+        let scale = ffmpeg
+            .encoder
+            .software_filter(rendition.width, rendition.height, self.color);
+        let graph = match burn_in {
+            // Bitmap subtitles are drawn at the source size before scaling.
+            // Blu-ray subtitles of cropped video keep the uncropped canvas,
+            // so it is aligned to the bottom, where the captions are.
+            BurnIn::Bitmap(id) => {
+                format!("[0:v:0][0:s:{id}]overlay=x=(W-w)/2:y=H-h:eof_action=pass,{scale}[burned]")
             }
-            Tracktype::Subtitle(external) => {
-                if video_metadata.unavailable_subs.contains(&track.id) {
-                    continue;
+            // The filter reads the subtitles itself, from the start, while
+            // the frames start at 0 after seeking, so they are moved back to
+            // their place in the file for it.
+            BurnIn::Text {
+                file,
+                stream,
+                fonts_dir,
+            } => {
+                let mut subtitles = format!("subtitles=filename={}", filter_path(file));
+                if let Some(stream) = stream {
+                    write!(subtitles, ":si={stream}").unwrap();
+                }
+                if let Some(fonts_dir) = fonts_dir {
+                    write!(subtitles, ":fontsdir={}", filter_path(fonts_dir)).unwrap();
                 }
-                let subtitle_stream =
-                    get_subtitle(path, track.id, external, start_timestamp, duration).await;
-                println!("Subtitle data: {}", subtitle_stream.data.len());
-                video_data.subtitle_data.push(subtitle_stream);
+                format!(
+                    "[0:v:0]setpts=PTS+{start_timestamp}/TB,{subtitles},setpts=PTS-{start_timestamp}/TB,{scale}[burned]"
+                )
             }
+        };
+        vec![
+            "-filter_complex".to_string(),
+            graph,
+            "-map".to_string(),
+            "[burned]".to_string(),
+        ]
+    }
+
+    fn codec_args(&self, ffmpeg: &FfmpegInfo) -> Vec<String> {
+        if self.stream_copy {
+            return vec!["-c:v".to_string(), "copy".to_string()];
         }
+        let rendition = &self.rendition;
+        let mut args = ffmpeg
+            .encoder
+            .encode_args(self.codec, rendition.max_bitrate, self.color);
+        args.extend(self.color.output_args());
+        if self.burn_in.is_none() {
+            args.extend([
+                "-vf".to_string(),
+                ffmpeg
+                    .encoder
+                    .video_filter(rendition.width, rendition.height, self.color),
+            ]);
+        }
+        args.extend([
+            "-force_key_frames".to_string(),
+            "expr:gte(t,n_forced*2)".to_string(),
+        ]);
+        args
     }
+}
 
-    Ok(video_data)
+/// Sample rate of re-encoded DASH audio, which the manifest advertises.
+/// Every codec we encode to supports it, Opus only it.
+pub const DASH_SAMPLE_RATE: u32 = 48_000;
+
+/// Per-request settings for an audio stream.
+#[derive(Debug, Clone, Default)]
+pub struct AudioOptions {
+    /// Copy the source stream instead of re-encoding it.
+    pub stream_copy: bool,
+    pub codec: AudioCodec,
+    /// Most channels to encode; downmixed to stereo when `None`.
+    pub max_channels: Option<u32>,
+    pub normalization: Normalization,
 }
 
-async fn get_video(path: &str, start_timestamp: f64, duration: f64) -> Vec<u8> {
-    let buffer = Arc::new(Mutex::new(Vec::new()));
-    let buffer_clone = buffer.clone();
-    let path = Arc::new(path.to_string());
-    // Spawn FFmpeg transcoding process
-    let handle = tokio::spawn(async move {
-        let mut ffmpeg = Command::new("ffmpeg-next")
-            .args(["-v", "error"])
-            .args(["-hwaccel", "cuda"])
-            .args(["-hwaccel_output_format", "cuda"])
-            .args(["-ss", &start_timestamp.to_string()])
-            .args(["-i", &path])
-            .args(["-t", &duration.to_string()])
-            .args(["-c:v", "hevc_nvenc"])
-            .args(["-crf", "20"])
-            .args(["-vf", "scale_cuda=1920:1080:format=yuv420p"])
-            .args(["-force_key_frames", "expr:gte(t,n_forced*2)"])
-            .args([
-                "-movflags",
-                "frag_keyframe+empty_moov+faststart+default_base_moof",
-            ])
-            .args(["-an"])
-            .args(["-f", "mp4"])
-            .args(["pipe:1"])
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to start FFmpeg");
-
-        if let Some(mut stdout) = ffmpeg.stdout.take() {
-            let mut read_buf = vec![0; 1024 * 1024 * 12];
-            loop {
-                match stdout.read(&mut read_buf).await {
-                    Ok(0) => {
-                        break;
-                    }
-                    Ok(bytes_read) => {
-                        let mut buffer_writer = buffer_clone.lock().await;
-                        buffer_writer.extend_from_slice(&read_buf[..bytes_read]);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to read FFmpeg stdout: {e}");
-                    }
-                }
-            }
+impl AudioOptions {
+    /// The codec re-encoded audio has in `container`.
+    pub fn output_codec(&self, container: Container) -> AudioCodec {
+        // Opus in MPEG-TS is poorly supported by HLS clients, so segments
+        // use AAC instead.
+        match container {
+            Container::MpegTs => AudioCodec::Aac,
+            Container::FragmentedMp4 | Container::DashSegment => self.codec,
+        }
+    }
+
+    /// The channel count re-encoded audio has in `container`, for a source
+    /// track with `channels`, if known.
+    pub fn output_channels(&self, container: Container, channels: Option<u32>) -> u32 {
+        // Never upmix: a stereo track stays stereo on a 5.1 client.
+        match self.max_channels {
+            Some(max) => channels.map_or(max, |channels| channels.min(max)),
+            None => 2,
+        }
+        .clamp(1, self.output_codec(container).max_channels())
+    }
+
+    /// `channels` is the channel count of the source track, if known.
+    fn codec_args(&self, container: Container, channels: Option<u32>) -> Vec<String> {
+        if self.stream_copy {
+            return vec!["-c:a".to_string(), "copy".to_string()];
+        }
+        let codec = self.output_codec(container);
+        let channels = self.output_channels(container, channels);
+        let mut args = vec![
+            "-c:a".to_string(),
+            codec.encoder_name().to_string(),
+            "-ac".to_string(),
+            channels.to_string(),
+        ];
+        if codec == AudioCodec::Opus && channels > 2 {
+            // libopus refuses surround layouts without a channel mapping.
+            args.extend(["-mapping_family".to_string(), "1".to_string()]);
+        }
+        if let Some(filter) = self.normalization.filter() {
+            args.extend(["-af".to_string(), filter.to_string()]);
+        }
+        if container == Container::DashSegment {
+            args.extend(["-ar".to_string(), DASH_SAMPLE_RATE.to_string()]);
         }
-    });
-    handle.await.unwrap();
-    let buffer_reader = buffer.lock().await;
-    buffer_reader.clone()
+        args
+    }
 }
 
-async fn get_audio(path: &str, id: u64, start_timestamp: f64, duration: f64) -> AudioData {
-    let buffer = Arc::new(Mutex::new(Vec::new()));
-    let buffer_clone = buffer.clone();
-    let path = Arc::new(path.to_string());
+#[derive(Serialize, Debug)]
+pub struct SubtitleData {
+    pub id: u64,
+    pub data: String,
+}
 
-    // Spawn FFmpeg transcoding process
-    let handle = tokio::spawn(async move {
-        let mut ffmpeg = Command::new("ffmpeg-next")
+/// Runs ffmpeg to completion and returns everything it wrote to stdout. If
+/// the caller stops waiting, the process is killed. With a `key`, the output
+/// comes from and goes to the segment cache. Fails if ffmpeg cannot be
+/// started or exits with an error, rather than returning partial output.
+pub(super) async fn run_to_end(
+    job: &TranscodeJob,
+    command: Command,
+    key: Option<SegmentKey>,
+) -> Result<Vec<u8>, String> {
+    if let Some(key) = &key {
+        if let Some(data) = job.cache().get(key).await {
+            return Ok(data.to_vec());
+        }
+    }
+    let mut process = job
+        .spawn(command)
+        .map_err(|e| format!("Failed to start FFmpeg: {e}"))?;
+    let mut data = Vec::new();
+    if let Some(mut stdout) = process.take_stdout() {
+        stdout
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| format!("Failed to read FFmpeg output: {e}"))?;
+    }
+    if !process.wait().await {
+        return Err("FFmpeg failed".to_string());
+    }
+    if let Some(key) = key {
+        job.cache().put(key, &data).await;
+    }
+    Ok(data)
+}
+
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// A file the chunk process writes a track to.
+struct ChunkOutput {
+    id: u64,
+    track: String,
+    file: PathBuf,
+}
+
+/// Everything one `/video` chunk contains.
+struct ChunkPlan<'a> {
+    path: &'a str,
+    start: f64,
+    duration: f64,
+    video: VideoOptions,
+    audio: AudioOptions,
+    /// Ids and channel counts of the audio tracks.
+    audio_tracks: Vec<(u64, Option<u32>)>,
+    /// Ids of the subtitle tracks, with the file of sidecar ones.
+    subtitle_tracks: Vec<(u64, Option<PathBuf>)>,
+}
+
+impl ChunkPlan<'_> {
+    /// A single ffmpeg that decodes the input once and writes every track of
+    /// the chunk: video to stdout, so it can be streamed as it is encoded,
+    /// and the other tracks to files in `dir`. Files rather than more pipes
+    /// keep ffmpeg from blocking on outputs nobody is reading yet.
+    fn command(&self, ffmpeg: &FfmpegInfo, dir: &Path) -> (Command, Vec<ChunkOutput>) {
+        let decode_args = self.video.decode_args(ffmpeg);
+        let start = self.start.to_string();
+        let mut command = Command::new(&ffmpeg.ffmpeg_path);
+        command
             .args(["-v", "error"])
-            .args(["-hwaccel", "cuda"])
-            .args(["-hwaccel_output_format", "cuda"])
-            .args(["-ss", &start_timestamp.to_string()])
-            .args(["-i", &path])
-            .args(["-t", &duration.to_string()])
-            .args(["-c:a", "libopus"])
-            .args(["-ac", "2"])
-            .args(["-af", "loudnorm"])
-            .args(["-map", format!("0:a:{id}").as_str()])
-            .args(["-force_key_frames", "expr:gte(t,n_forced*2)"])
-            .args([
-                "-movflags",
-                "frag_keyframe+empty_moov+faststart+default_base_moof",
-            ])
-            .args(["-vn"])
-            .args(["-f", "mp4"])
-            .args(["pipe:1"])
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to start FFmpeg");
-
-        if let Some(mut stdout) = ffmpeg.stdout.take() {
-            let mut read_buf = vec![0; 1024 * 1024 * 2];
-            loop {
-                match stdout.read(&mut read_buf).await {
-                    Ok(0) => {
-                        break;
-                    }
-                    Ok(bytes_read) => {
-                        let mut buffer_writer = buffer_clone.lock().await;
-                        buffer_writer.extend_from_slice(&read_buf[..bytes_read]);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to read FFmpeg stdout: {e}");
-                    }
+            .args(decode_args)
+            .args(["-ss", &start])
+            .args(["-i", self.path]);
+        // Sidecar files are added as further inputs, numbered from 1.
+        let mut sidecar_inputs = 0;
+        let mut subtitle_streams = Vec::new();
+        for (id, sidecar) in &self.subtitle_tracks {
+            let stream = match sidecar {
+                Some(sub_path) => {
+                    command
+                        .args(["-ss", &start])
+                        .args(["-i".as_ref(), sub_path.as_os_str()]);
+                    sidecar_inputs += 1;
+                    format!("{sidecar_inputs}:s:0")
                 }
-            }
+                None => format!("0:s:{id}"),
+            };
+            subtitle_streams.push((*id, stream));
         }
-    });
-    handle.await.unwrap();
-    let buffer_reader = buffer.lock().await;
-    let data = buffer_reader.clone();
-    AudioData { id, data }
+
+        command
+            .args(video_output_args(
+                ffmpeg,
+                self.start,
+                self.duration,
+                Container::FragmentedMp4,
+                &self.video,
+            ))
+            .args(["pipe:1"]);
+
+        let mut outputs = Vec::new();
+        for &(id, channels) in &self.audio_tracks {
+            let file = dir.join(format!("audio-{id}.mp4"));
+            command
+                .args(audio_output_args(
+                    id,
+                    channels,
+                    self.start,
+                    self.duration,
+                    Container::FragmentedMp4,
+                    &self.audio,
+                ))
+                .arg(&file);
+            outputs.push(ChunkOutput {
+                id,
+                track: format!("audio:{id}"),
+                file,
+            });
+        }
+        for (id, stream) in subtitle_streams {
+            let file = dir.join(format!("subtitle-{id}.vtt"));
+            command
+                .args(subtitle_output_args(&stream, self.start, self.duration))
+                .arg(&file);
+            outputs.push(ChunkOutput {
+                id,
+                track: format!("subtitle:{id}"),
+                file,
+            });
+        }
+        (command, outputs)
+    }
 }
 
-async fn get_subtitle(
+pub async fn stream_video_data(
+    ffmpeg: Arc<FfmpegInfo>,
+    job: TranscodeJob,
     path: &str,
-    id: u64,
-    is_external: bool,
     start_timestamp: f64,
     duration: f64,
-) -> SubtitleData {
-    if is_external {
-        let video_path = std::path::Path::new(path);
-        let video_directory = video_path.parent().unwrap();
-        let mut sub_path = None;
-        for file in video_directory.read_dir().unwrap() {
-            let file_path = file.unwrap().path();
-            if file_path.extension().unwrap() == "srt" {
-                sub_path = Some(file_path);
-            }
+    client: &ClientOptions,
+) -> Result<ByteStream, String> {
+    let video_metadata = get_video_metadata(&ffmpeg, path, &client.profile).await?;
+    let mut video_options = video_metadata.transcode_options(client.quality.as_deref())?;
+    // Only an explicitly requested quality can rule out copying; without one
+    // the playback decision stands.
+    video_options.stream_copy = video_metadata.playback.mode != PlaybackMode::Transcode
+        && (client.quality.is_none() || video_metadata.source_fits(&video_options.rendition));
+    if let Some(id) = client.burn_subtitle {
+        if video_metadata.subtitle_track(id).is_none() {
+            return Err(format!("No subtitle track {id}"));
         }
-        if sub_path.is_none() {
-            return SubtitleData {
-                id,
-                data: String::new(),
+        video_options.burn_in = Some(if video_metadata.unavailable_subs.contains(&id) {
+            BurnIn::Bitmap(id)
+        } else if let Some(sidecar) = video_metadata.sidecar_path(id) {
+            let fonts_dir = if video_metadata.fonts.is_empty() {
+                None
+            } else {
+                Some(attachments::fonts_dir(&ffmpeg, &job, path, &video_metadata.fonts).await?)
             };
-        }
-        let sub_path = sub_path.unwrap();
-        let buffer = Arc::new(Mutex::new(Vec::new()));
-        let buffer_clone = buffer.clone();
-        let path = Arc::new(sub_path.to_string_lossy().to_string());
-
-        // Spawn FFmpeg transcoding process
-        let handle = tokio::spawn(async move {
-            let mut ffmpeg = Command::new("ffmpeg-next")
-                .args(["-v", "error"])
-                .args(["-ss", &start_timestamp.to_string()])
-                .args(["-i", &path])
-                .args(["-output_ts_offset", &start_timestamp.to_string()])
-                .args(["-t", &duration.to_string()])
-                .args(["-c:s", "webvtt"])
-                .args(["-f", "webvtt"])
-                .args(["pipe:1"])
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failed to start FFmpeg");
-
-            if let Some(mut stdout) = ffmpeg.stdout.take() {
-                let mut read_buf = vec![0; 1024 * 1024 * 2];
-                loop {
-                    match stdout.read(&mut read_buf).await {
-                        Ok(0) => {
-                            break;
-                        }
-                        Ok(bytes_read) => {
-                            let mut buffer_writer = buffer_clone.lock().await;
-                            buffer_writer.extend_from_slice(&read_buf[..bytes_read]);
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to read FFmpeg stdout: {e}");
-                        }
-                    }
-                }
+            BurnIn::Text {
+                file: sidecar.to_path_buf(),
+                stream: None,
+                fonts_dir,
+            }
+        } else {
+            // libass loads the fonts attached to the video itself.
+            BurnIn::Text {
+                file: PathBuf::from(path),
+                stream: Some(id),
+                fonts_dir: None,
             }
         });
-        handle.await.unwrap();
-        let buffer_reader = buffer.lock().await;
-        let binary = buffer_reader.clone();
+        video_options.stream_copy = false;
+    }
+    if !video_options.stream_copy {
+        video_metadata.check_reencode()?;
+    }
+    let audio_options = video_metadata.audio_options();
+    println!("Duration: {duration}");
 
-        let data = String::from_utf8_lossy(&binary).to_string();
+    let audio_tracks: Vec<(u64, Option<u32>)> = video_metadata
+        .tracks
+        .iter()
+        .filter(|track| track.kind == Tracktype::Audio)
+        .map(|track| (track.id, track.channels))
+        .collect();
+    let subtitle_tracks: Vec<(u64, Option<PathBuf>)> = video_metadata
+        .tracks
+        .iter()
+        .filter(|track| {
+            matches!(track.kind, Tracktype::Subtitle(_))
+                && !video_metadata.unavailable_subs.contains(&track.id)
+        })
+        .map(|track| {
+            let sidecar = video_metadata.sidecar_path(track.id);
+            (track.id, sidecar.map(Path::to_path_buf))
+        })
+        .collect();
 
-        SubtitleData { id, data }
+    let video_codec = if video_options.stream_copy {
+        video_metadata.video_codec.clone()
     } else {
-        let buffer = Arc::new(Mutex::new(Vec::new()));
-        let buffer_clone = buffer.clone();
-        let path = Arc::new(path.to_string());
-
-        // Spawn FFmpeg transcoding process
-        let handle = tokio::spawn(async move {
-            let mut ffmpeg = Command::new("ffmpeg-next")
-                .args(["-v", "error"])
-                .args(["-ss", &start_timestamp.to_string()])
-                .args(["-i", &path])
-                .args(["-output_ts_offset", &start_timestamp.to_string()])
-                .args(["-t", &duration.to_string()])
-                .args(["-map", format!("0:s:{id}").as_str()])
-                .args(["-c:s", "webvtt"])
-                .args(["-f", "webvtt"])
-                .args(["pipe:1"])
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failed to start FFmpeg");
-
-            if let Some(mut stdout) = ffmpeg.stdout.take() {
-                let mut read_buf = vec![0; 1024 * 1024 * 2];
-                loop {
-                    match stdout.read(&mut read_buf).await {
-                        Ok(0) => {
-                            break;
-                        }
-                        Ok(bytes_read) => {
-                            let mut buffer_writer = buffer_clone.lock().await;
-                            buffer_writer.extend_from_slice(&read_buf[..bytes_read]);
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to read FFmpeg stdout: {e}");
-                        }
-                    }
+        video_options
+            .codec
+            .codec_string(video_options.color)
+            .to_string()
+    };
+    let language = |id: u64, kind: fn(&Tracktype) -> bool| {
+        video_metadata
+            .tracks
+            .iter()
+            .find(|track| track.id == id && kind(&track.kind))
+            .and_then(|track| track.language.clone())
+    };
+    let mut track_headers = vec![TrackHeader {
+        id: 0,
+        kind: TrackKind::Video,
+        codec: video_codec,
+        language: language(0, |kind| *kind == Tracktype::Video),
+    }];
+    track_headers.extend(audio_tracks.iter().map(|&(id, _)| TrackHeader {
+        id,
+        kind: TrackKind::Audio,
+        codec: video_metadata.audio_codec.clone(),
+        language: language(id, |kind| *kind == Tracktype::Audio),
+    }));
+    track_headers.extend(subtitle_tracks.iter().map(|&(id, _)| TrackHeader {
+        id,
+        kind: TrackKind::Subtitle,
+        codec: "wvtt".to_string(),
+        language: language(id, |kind| matches!(kind, Tracktype::Subtitle(_))),
+    }));
+    let framing = client.framing;
+    let header = stream::once(future::ready(Ok(
+        framing.header(start_timestamp, &track_headers)
+    )));
+
+    let plan = ChunkPlan {
+        path,
+        start: start_timestamp,
+        duration,
+        video: video_options,
+        audio: audio_options,
+        audio_tracks,
+        subtitle_tracks,
+    };
+    let dir = tempfile::Builder::new()
+        .prefix("nexus-chunk-")
+        .tempdir()
+        .map_err(|e| format!("Failed to create chunk directory: {e}"))?;
+    let (command, outputs) = plan.command(&ffmpeg, dir.path());
+    // The directory differs every time, so the cache keys come from the
+    // same command with a fixed one.
+    let (key_command, _) = plan.command(&ffmpeg, Path::new("chunk"));
+    let video_key = SegmentKey::new(
+        path,
+        "video".to_string(),
+        start_timestamp,
+        duration,
+        &key_command,
+    )
+    .await;
+    let mut keys: Vec<Option<SegmentKey>> = Vec::new();
+    for output in &outputs {
+        keys.push(
+            SegmentKey::new(
+                path,
+                output.track.clone(),
+                start_timestamp,
+                duration,
+                &key_command,
+            )
+            .await,
+        );
+    }
+
+    let cache = job.cache();
+    let mut cached = Vec::new();
+    for key in std::iter::once(&video_key).chain(&keys) {
+        let Some(key) = key else { break };
+        let Some(data) = cache.get(key).await else {
+            break;
+        };
+        cached.push(data);
+    }
+    if cached.len() == outputs.len() + 1 {
+        let ids = std::iter::once(0).chain(outputs.iter().map(|output| output.id));
+        let tracks: Vec<ByteStream> = ids
+            .zip(cached)
+            .map(|(id, data)| framing.track(id, stream::once(future::ready(Ok(data))).boxed()))
+            .collect();
+        return Ok(header.chain(stream::iter(tracks).flatten()).boxed());
+    }
+
+    let mut process = job
+        .spawn(command)
+        .map_err(|e| format!("Failed to start FFmpeg: {e}"))?;
+    let stdout = process
+        .take_stdout()
+        .ok_or_else(|| "FFmpeg stdout is not piped".to_string())?;
+    // The video is copied into the cache as it streams, and the copy is
+    // handed over once the stream has ended.
+    let tee = match video_key {
+        Some(_) => cache.create_partial().await,
+        None => None,
+    };
+    let (tee_done, tee_result) = oneshot::channel();
+    let video = stream::unfold(
+        (ReaderStream::new(stdout), tee, Some(tee_done)),
+        |(mut reader, mut tee, mut tee_done)| async move {
+            let Some(chunk) = reader.next().await else {
+                if let Some(tee_done) = tee_done.take() {
+                    let _ = tee_done.send(tee.take());
+                }
+                return None;
+            };
+            if let (Ok(data), Some(partial)) = (&chunk, tee.as_mut()) {
+                if let Err(e) = partial.write(data).await {
+                    eprintln!("Failed to write cached segment: {e}");
+                    tee = None;
                 }
             }
-        });
-        handle.await.unwrap();
-        let buffer_reader = buffer.lock().await;
-        let binary = buffer_reader.clone();
+            Some((chunk, (reader, tee, tee_done)))
+        },
+    );
 
-        let data = String::from_utf8_lossy(&binary).to_string();
+    // Owns the job and the directory, so dropping the response body, e.g.
+    // when the player seeks away, kills ffmpeg and removes its files.
+    // If ffmpeg fails, the body ends with an error instead of the other
+    // tracks, so the client never takes missing tracks for empty ones.
+    let other_tracks = stream::once(async move {
+        let failed = |message: String| -> ByteStream {
+            eprintln!("Video data error: {message}");
+            stream::once(future::ready(Err(std::io::Error::other(message)))).boxed()
+        };
+        if !process.wait().await {
+            return failed("FFmpeg failed".to_string());
+        }
+        if let (Some(key), Ok(Some(partial))) = (video_key, tee_result.await) {
+            cache.put_file(key, partial).await;
+        }
+        let mut tracks = Vec::new();
+        for (output, key) in outputs.into_iter().zip(keys) {
+            let data = match tokio::fs::read(&output.file).await {
+                Ok(data) => data,
+                Err(e) => return failed(format!("Failed to read {}: {e}", output.track)),
+            };
+            if let Some(key) = key {
+                cache.put(key, &data).await;
+            }
+            tracks.push(framing.track(
+                output.id,
+                stream::once(future::ready(Ok(Bytes::from(data)))).boxed(),
+            ));
+        }
+        drop(dir);
+        drop(job);
+        stream::iter(tracks).flatten().boxed()
+    })
+    .flatten();
 
-        SubtitleData { id, data }
-    }
+    Ok(header
+        .chain(framing.track(0, video.boxed()))
+        .chain(other_tracks)
+        .boxed())
+}
+
+fn video_output_args(
+    ffmpeg: &FfmpegInfo,
+    start_timestamp: f64,
+    duration: f64,
+    container: Container,
+    options: &VideoOptions,
+) -> Vec<String> {
+    let mut args = options.map_args(ffmpeg, start_timestamp);
+    args.extend(["-t".to_string(), duration.to_string()]);
+    args.extend(options.codec_args(ffmpeg));
+    args.push("-an".to_string());
+    args.extend(container.muxer_args(start_timestamp));
+    args
+}
+
+/// `channels` is the channel count of the source track, if known.
+fn audio_output_args(
+    id: u64,
+    channels: Option<u32>,
+    start_timestamp: f64,
+    duration: f64,
+    container: Container,
+    options: &AudioOptions,
+) -> Vec<String> {
+    let mut args = vec![
+        "-map".to_string(),
+        format!("0:a:{id}"),
+        "-t".to_string(),
+        duration.to_string(),
+    ];
+    args.extend(options.codec_args(container, channels));
+    args.push("-vn".to_string());
+    args.extend(container.muxer_args(start_timestamp));
+    args
+}
+
+fn subtitle_output_args(stream: &str, start_timestamp: f64, duration: f64) -> Vec<String> {
+    [
+        "-map",
+        stream,
+        "-output_ts_offset",
+        &start_timestamp.to_string(),
+        "-t",
+        &duration.to_string(),
+        "-c:s",
+        "webvtt",
+        "-f",
+        "webvtt",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+fn video_command(
+    ffmpeg: &FfmpegInfo,
+    path: &str,
+    start_timestamp: f64,
+    duration: f64,
+    container: Container,
+    options: &VideoOptions,
+) -> Command {
+    let decode_args = options.decode_args(ffmpeg);
+    let mut command = Command::new(&ffmpeg.ffmpeg_path);
+    command
+        .args(["-v", "error"])
+        .args(decode_args)
+        .args(["-ss", &start_timestamp.to_string()])
+        .args(["-i", path])
+        .args(video_output_args(
+            ffmpeg,
+            start_timestamp,
+            duration,
+            container,
+            options,
+        ))
+        .args(["pipe:1"]);
+    command
+}
+
+#[allow(clippy::too_many_arguments)]
+fn audio_command(
+    ffmpeg: &FfmpegInfo,
+    path: &str,
+    id: u64,
+    channels: Option<u32>,
+    start_timestamp: f64,
+    duration: f64,
+    container: Container,
+    options: &AudioOptions,
+) -> Command {
+    let mut command = Command::new(&ffmpeg.ffmpeg_path);
+    command
+        .args(["-v", "error"])
+        .args(["-ss", &start_timestamp.to_string()])
+        .args(["-i", path])
+        .args(audio_output_args(
+            id,
+            channels,
+            start_timestamp,
+            duration,
+            container,
+            options,
+        ))
+        .args(["pipe:1"]);
+    command
+}
+
+/// Builds the WebVTT conversion for a subtitle track, read from `sidecar`
+/// if it is a separate file.
+fn subtitle_command(
+    ffmpeg: &FfmpegInfo,
+    path: &str,
+    id: u64,
+    sidecar: Option<&Path>,
+    start_timestamp: f64,
+    duration: f64,
+) -> Command {
+    let mut command = Command::new(&ffmpeg.ffmpeg_path);
+    command
+        .args(["-v", "error"])
+        .args(["-ss", &start_timestamp.to_string()]);
+    let stream = match sidecar {
+        Some(sub_path) => {
+            command.args(["-i".as_ref(), sub_path.as_os_str()]);
+            "0:s:0".to_string()
+        }
+        None => {
+            command.args(["-i", path]);
+            format!("0:s:{id}")
+        }
+    };
+    command
+        .args(subtitle_output_args(&stream, start_timestamp, duration))
+        .args(["pipe:1"]);
+    command
+}
+
+pub async fn get_video(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    path: &str,
+    start_timestamp: f64,
+    duration: f64,
+    container: Container,
+    options: &VideoOptions,
+) -> Result<Vec<u8>, String> {
+    let command = video_command(ffmpeg, path, start_timestamp, duration, container, options);
+    let key = SegmentKey::new(
+        path,
+        "video".to_string(),
+        start_timestamp,
+        duration,
+        &command,
+    )
+    .await;
+    run_to_end(job, command, key).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn get_audio(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    path: &str,
+    id: u64,
+    channels: Option<u32>,
+    start_timestamp: f64,
+    duration: f64,
+    container: Container,
+    options: &AudioOptions,
+) -> Result<Vec<u8>, String> {
+    let command = audio_command(
+        ffmpeg,
+        path,
+        id,
+        channels,
+        start_timestamp,
+        duration,
+        container,
+        options,
+    );
+    let key = SegmentKey::new(
+        path,
+        format!("audio:{id}"),
+        start_timestamp,
+        duration,
+        &command,
+    )
+    .await;
+    run_to_end(job, command, key).await
+}
+
+pub async fn get_subtitle(
+    ffmpeg: &FfmpegInfo,
+    job: &TranscodeJob,
+    path: &str,
+    id: u64,
+    sidecar: Option<&Path>,
+    start_timestamp: f64,
+    duration: f64,
+) -> Result<SubtitleData, String> {
+    let command = subtitle_command(ffmpeg, path, id, sidecar, start_timestamp, duration);
+    let key = SegmentKey::new(
+        path,
+        format!("subtitle:{id}"),
+        start_timestamp,
+        duration,
+        &command,
+    )
+    .await;
+    let data = String::from_utf8_lossy(&run_to_end(job, command, key).await?).to_string();
+    Ok(SubtitleData { id, data })
 }